@@ -1,31 +1,68 @@
 use anyhow::{Context, Result, bail};
-use jiff::Timestamp;
+use jiff::{SignedDuration, Timestamp};
+use log::{debug, error, info, warn};
 use serde::{Deserialize, Serialize, Serializer};
+use std::ffi::OsStr;
 use std::fmt::{self, Display};
 use std::fs::File;
 use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
 use std::time::{Duration, SystemTime};
 use uuid::Uuid;
 
-macro_rules! defer {
-    ($($body:tt)*) => {
-        let _guard = {
-            struct D<F: FnMut()>(F);
-            impl<F: FnMut()> Drop for D<F> {
-                fn drop(&mut self) { (self.0)(); }
-            }
-            D(|| { $($body)* })
-        };
-    };
-}
-
-#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum MediaType {
     Audio,
     Video,
     Image,
+    /// Subtitles (e.g. a `.vtt` or `.srt` file), only accepted with
+    /// [`GroupOptions::subtitles`]
+    Subtitle,
+}
+
+impl Display for MediaType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            MediaType::Audio => "audio",
+            MediaType::Video => "video",
+            MediaType::Image => "image",
+            MediaType::Subtitle => "subtitle",
+        })
+    }
+}
+
+impl FromStr for MediaType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "audio" => MediaType::Audio,
+            "video" => MediaType::Video,
+            "image" => MediaType::Image,
+            "subtitle" => MediaType::Subtitle,
+            other => bail!("Unknown media type {other}, expected audio, video, image, or subtitle"),
+        })
+    }
+}
+
+#[test]
+fn media_type_round_trip() {
+    for media in [
+        MediaType::Audio,
+        MediaType::Video,
+        MediaType::Image,
+        MediaType::Subtitle,
+    ] {
+        assert_eq!(media.to_string().parse::<MediaType>().unwrap(), media);
+        let json = serde_json::to_string(&media).unwrap();
+        assert_eq!(json, format!("\"{media}\""));
+        assert_eq!(serde_json::from_str::<MediaType>(&json).unwrap(), media);
+    }
+    assert!("Audio".parse::<MediaType>().is_err());
 }
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
@@ -36,14 +73,19 @@ pub struct Resolution {
 
 impl PartialOrd for Resolution {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        (self.width as u32 * self.height as u32)
-            .partial_cmp(&(other.width as u32 * other.height as u32))
+        Some(self.cmp(other))
     }
 }
 
+/// Ordered by pixel count, and resolutions of the same pixel count but a different shape by
+/// width and then height, consistent with [`Eq`].
 impl Ord for Resolution {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        (self.width as u32 * self.height as u32).cmp(&(other.width as u32 * other.height as u32))
+        let area = |res: &Self| res.width as u32 * res.height as u32;
+        area(self)
+            .cmp(&area(other))
+            .then(self.width.cmp(&other.width))
+            .then(self.height.cmp(&other.height))
     }
 }
 
@@ -60,6 +102,28 @@ fn resolution_cmp() {
         })
         .is_gt()
     );
+
+    // Equal areas of different shapes are still ordered, the same way regardless of order
+    let res = |width, height| Resolution { width, height };
+    assert!(res(640, 360) > res(480, 480));
+    assert!(res(480, 480) < res(640, 360));
+    assert!(res(360, 640) < res(480, 480));
+    assert_ne!(res(640, 360).cmp(&res(360, 640)), std::cmp::Ordering::Equal);
+    let mut sorted = [res(480, 480), res(640, 360), res(360, 640), res(1280, 720)];
+    sorted.sort();
+    assert_eq!(
+        sorted,
+        [res(360, 640), res(480, 480), res(640, 360), res(1280, 720)]
+    );
+    let max = [res(480, 480), res(640, 360), res(360, 640)]
+        .into_iter()
+        .max();
+    assert_eq!(
+        max,
+        [res(360, 640), res(640, 360), res(480, 480)]
+            .into_iter()
+            .max()
+    );
 }
 
 #[test]
@@ -74,6 +138,89 @@ fn resolution_opt_cmp() {
     );
 }
 
+impl Resolution {
+    /// The largest resolution with the same aspect ratio as `self` that fits within `max`.
+    ///
+    /// Both dimensions are rounded to even numbers (as most codecs require for chroma
+    /// subsampled output), without exceeding `max` and never going below 2. Note that this
+    /// scales up as well as down; check [`Resolution::fits_within()`] first to only ever
+    /// downscale.
+    pub fn scaled_to_fit(&self, max: Resolution) -> Resolution {
+        if self.width == 0 || self.height == 0 {
+            return *self;
+        }
+
+        let scale = f64::min(
+            max.width as f64 / self.width as f64,
+            max.height as f64 / self.height as f64,
+        );
+        let even = |len: u16, max: u16| {
+            let len = ((len as f64 * scale / 2.0).round() * 2.0) as u16;
+            len.min(max & !1).max(2)
+        };
+
+        Resolution {
+            width: even(self.width, max.width),
+            height: even(self.height, max.height),
+        }
+    }
+
+    /// Whether `self` is no larger than `max` in either dimension.
+    pub fn fits_within(&self, max: Resolution) -> bool {
+        self.width <= max.width && self.height <= max.height
+    }
+
+    /// Whether both dimensions are even, as required by most codecs for chroma subsampled
+    /// output (e.g. `yuv420p`).
+    pub fn is_even(&self) -> bool {
+        self.width.is_multiple_of(2) && self.height.is_multiple_of(2)
+    }
+
+    /// The ratio of the width to the height, e.g. about `1.78` for 16:9 and `0.56` for 9:16.
+    pub fn aspect_ratio(&self) -> f32 {
+        self.width as f32 / self.height as f32
+    }
+}
+
+#[test]
+fn resolution_aspect_ratio() {
+    let res = |width, height| Resolution { width, height };
+
+    assert_eq!(res(1080, 1080).aspect_ratio(), 1.0);
+    assert!((res(1920, 1080).aspect_ratio() - 16.0 / 9.0).abs() < f32::EPSILON);
+    assert!((res(1080, 1920).aspect_ratio() - 9.0 / 16.0).abs() < f32::EPSILON);
+}
+
+#[test]
+fn resolution_scaled_to_fit() {
+    let res = |width, height| Resolution { width, height };
+
+    // Downscaling preserves the aspect ratio, constrained by either dimension
+    assert_eq!(res(1920, 1080).scaled_to_fit(res(640, 640)), res(640, 360));
+    assert_eq!(res(1080, 1920).scaled_to_fit(res(320, 320)), res(180, 320));
+    // Upscaling works too
+    assert_eq!(res(512, 512).scaled_to_fit(res(432, 800)), res(432, 432));
+    assert_eq!(res(256, 256).scaled_to_fit(res(432, 432)), res(432, 432));
+}
+
+#[test]
+fn resolution_scaled_to_fit_even() {
+    let res = |width, height| Resolution { width, height };
+
+    // 56.25 rounds to the nearest even number
+    assert_eq!(res(1280, 720).scaled_to_fit(res(100, 100)), res(100, 56));
+    // 225 rounds up to 226
+    assert_eq!(res(640, 480).scaled_to_fit(res(300, 300)), res(300, 226));
+    // Rounding never exceeds an odd `max`
+    assert_eq!(
+        res(1081, 1921).scaled_to_fit(res(1081, 1921)),
+        res(1080, 1920)
+    );
+    assert_eq!(res(1000, 10).scaled_to_fit(res(101, 101)), res(100, 2));
+    // Dimensions never collapse to zero
+    assert_eq!(res(1920, 2).scaled_to_fit(res(960, 960)), res(960, 2));
+}
+
 impl Display for Resolution {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
@@ -85,193 +232,3186 @@ impl Display for Resolution {
     }
 }
 
-/// Group paths into files belonging to the same attachment
-pub fn group<P: AsRef<Path>>(paths: &[P]) -> Result<Vec<Vec<MediaInfo>>> {
-    let mut media_info = Vec::with_capacity(paths.len());
+impl FromStr for Resolution {
+    type Err = anyhow::Error;
 
-    for path in paths {
-        let path = path.as_ref();
-        let mi = identify(path).with_context(|| format!("Error identifying {}", path.display()))?;
-        media_info.push(mi);
+    fn from_str(s: &str) -> Result<Self> {
+        let Some((width, height)) = s.split_once('x') else {
+            bail!("Invalid resolution {s}, expected e.g. 1920x1080");
+        };
+        Ok(Resolution {
+            width: width.parse().context("Invalid resolution width")?,
+            height: height.parse().context("Invalid resolution height")?,
+        })
     }
+}
 
-    // Sort by duration to ensure we process similar files together first
-    media_info.sort_by_key(|mi| std::cmp::Reverse(mi.duration));
+impl Serialize for Resolution {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
 
-    let mut groups: Vec<Vec<MediaInfo>> = Vec::new();
+impl<'de> Deserialize<'de> for Resolution {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
 
-    // Max deviation allowed between two encodes of the same original media
-    let max_delta = Duration::from_secs_f64(0.7);
+/// A frame rate, as the ratio ffprobe reports it as (e.g. `30000/1001`).
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct FrameRate {
+    pub num: u32,
+    pub den: u32,
+}
 
-    for mi in media_info {
-        let mut best_match: Option<(usize, Duration)> = None; // (idx, Duration)
+impl FrameRate {
+    pub fn fps(&self) -> f64 {
+        self.num as f64 / self.den as f64
+    }
+}
 
-        for (idx, group) in groups.iter().enumerate() {
-            // If audio/image, group must not already have audio/image.
-            // If video, group must not already have this resolution.
-            let already_has_resolution = group.iter().any(|other| match mi.media {
-                MediaType::Audio => other.is_audio(),
-                MediaType::Image => other.is_image(),
-                MediaType::Video => other.resolution == mi.resolution,
-            });
+impl Display for FrameRate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // e.g. 29.97 or 30
+        write!(f, "{}", (self.fps() * 100.0).round() / 100.0)
+    }
+}
 
-            if already_has_resolution {
-                continue;
-            }
+impl FromStr for FrameRate {
+    type Err = anyhow::Error;
 
-            // Hack to skip thumbnails
-            if mi.is_image() {
-                if best_match.is_none()
-                    && group.iter().any(|other| other.resolution == mi.resolution)
-                {
-                    best_match = Some((idx, Default::default()));
-                }
-                continue;
-            }
+    fn from_str(s: &str) -> Result<Self> {
+        let Some((num, den)) = s.split_once('/') else {
+            bail!("Invalid frame rate {s}, expected e.g. 30000/1001");
+        };
+        let rate = FrameRate {
+            num: num.parse().context("Invalid frame rate numerator")?,
+            den: den.parse().context("Invalid frame rate denominator")?,
+        };
+        // ffprobe reports `0/0` for streams without a frame rate, e.g. images
+        if rate.num == 0 || rate.den == 0 {
+            bail!("Invalid frame rate {s}, must not be zero");
+        }
+        Ok(rate)
+    }
+}
 
-            let delta = mi.duration.abs_diff(group[0].duration);
-            if delta <= max_delta {
-                // Take closest matching duration
-                match best_match {
-                    None => best_match = Some((idx, delta)),
-                    Some((_, best_delta)) => {
-                        if delta < best_delta {
-                            best_match = Some((idx, delta));
-                        }
-                    }
-                }
-            }
+/// Serialized as the exact ratio (e.g. `30000/1001`) rather than as displayed.
+impl Serialize for FrameRate {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&format_args!("{}/{}", self.num, self.den))
+    }
+}
+
+impl<'de> Deserialize<'de> for FrameRate {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Videos whose frame rates differ by more than this factor are unlikely to be encodes of the
+/// same content.
+const MAX_FRAME_RATE_RATIO: f64 = 1.1;
+
+/// Whether `a` and `b` both have a known frame rate, materially different from the other's.
+fn frame_rates_differ(a: &MediaInfo, b: &MediaInfo) -> bool {
+    match (a.frame_rate, b.frame_rate) {
+        (Some(a), Some(b)) => a.fps().max(b.fps()) / a.fps().min(b.fps()) > MAX_FRAME_RATE_RATIO,
+        _ => false,
+    }
+}
+
+/// Videos whose aspect ratios differ by more than this aren't encodes of the same content,
+/// while encodes scaled to even (or otherwise rounded) dimensions, e.g. 854x480 of 1920x1080,
+/// differ by less.
+const MAX_ASPECT_RATIO_DELTA: f32 = 0.01;
+
+/// Whether `a` and `b` both have a known resolution, of a different aspect ratio than the
+/// other's, e.g. a landscape and a portrait video.
+fn aspect_ratios_differ(a: &MediaInfo, b: &MediaInfo) -> bool {
+    match (a.resolution, b.resolution) {
+        (Some(a), Some(b)) if a.width > 0 && a.height > 0 && b.width > 0 && b.height > 0 => {
+            (a.aspect_ratio() - b.aspect_ratio()).abs() > MAX_ASPECT_RATIO_DELTA
         }
+        _ => false,
+    }
+}
 
-        if let Some((idx, _)) = best_match {
-            groups[idx].push(mi);
-        } else {
-            // No compatible group found, create a new one
-            groups.push(vec![mi]);
+/// A diagnostic about a non-fatal condition encountered during processing.
+#[derive(Debug, Clone, Serialize)]
+pub struct Warning {
+    /// A stable, machine-readable identifier for the kind of warning
+    pub code: &'static str,
+    pub message: String,
+    /// The file the warning pertains to, if any
+    pub path: Option<PathBuf>,
+}
+
+/// Collects [`Warning`]s across threads. Unless [`quiet`](Warnings::quiet), warnings are also
+/// logged (at the warn level) as they're reported.
+#[derive(Debug, Default)]
+pub struct Warnings {
+    warnings: Mutex<Vec<Warning>>,
+    quiet: bool,
+}
+
+impl Warnings {
+    /// Collects warnings without printing them.
+    pub fn quiet() -> Self {
+        Warnings {
+            quiet: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn warn(&self, code: &'static str, path: Option<&Path>, message: String) {
+        if !self.quiet {
+            warn!("{message}");
+        }
+        self.warnings.lock().unwrap().push(Warning {
+            code,
+            message,
+            path: path.map(Path::to_owned),
+        });
+    }
+
+    pub fn into_vec(self) -> Vec<Warning> {
+        self.warnings.into_inner().unwrap()
+    }
+}
+
+/// How [`group()`] decides which inputs belong to the same attachment.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum GroupKey {
+    /// Inputs of (nearly) the same duration belong together.
+    #[default]
+    Duration,
+    /// Inputs sharing the same [`name_prefix()`] belong together.
+    Prefix,
+    /// Inputs captured at (nearly) the same time belong together.
+    Timestamp,
+    /// Group by prefix if the inputs are named with one, otherwise by duration, and never group
+    /// inputs with very different timestamps.
+    Auto,
+}
+
+/// Max deviation allowed between the durations of two encodes of the same original media, by
+/// default (see [`GroupOptions::max_delta`])
+pub const MAX_DURATION_DELTA: Duration = Duration::from_millis(700);
+
+impl GroupKey {
+    /// The key [`GroupKey::Auto`] stands for, depending on whether all inputs are named with a
+    /// [`name_prefix()`].
+    fn resolve(self, all_prefixed: bool) -> GroupKey {
+        match self {
+            GroupKey::Auto if all_prefixed => GroupKey::Prefix,
+            GroupKey::Auto => GroupKey::Duration,
+            key => key,
         }
     }
+}
+
+/// What [`group()`] compares inputs by to tell whether they're the same content.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MatchMode {
+    /// Inputs are compared by their [`GroupKey`] alone.
+    #[default]
+    Duration,
+    /// Videos must also look alike, going by their [`MediaInfo::fingerprint`]s. Inputs without
+    /// one (i.e. audio, images, and videos that couldn't be fingerprinted) are compared by their
+    /// [`GroupKey`] alone.
+    Content,
+}
+
+/// Where in a video (in percent of its duration) the frames of its [`fingerprint()`] are taken.
+const FINGERPRINT_POSITIONS: [f64; 3] = [25.0, 50.0, 75.0];
 
-    let max_divergence = groups
+/// The most bits the frame hashes of two [`fingerprint()`]s may differ by on average for the
+/// videos to be considered the same content.
+const MAX_FINGERPRINT_DISTANCE: u32 = 10;
+
+/// Fingerprints the content of video `mi`, as the [`perceptual_hash()`]es of a few frames taken
+/// throughout it.
+pub fn fingerprint(mi: &MediaInfo, tools: &Tools) -> Result<Vec<u64>> {
+    FINGERPRINT_POSITIONS
         .iter()
-        .map(|g| {
-            let mut candidates = g.iter().filter(|mi| !mi.is_image());
-            // Members are inserted by decreasing duration
-            let first = candidates.next();
-            let last = candidates.last();
-            if let (Some(first), Some(last)) = (first, last) {
-                first.duration - last.duration
-            } else {
-                // Less than two non-image files in group
-                Duration::ZERO
-            }
+        .map(|&percent| {
+            let position = ThumbnailPosition::Percent(percent);
+            perceptual_hash(&mi.path, mi.duration, position, tools)
         })
-        .max();
+        .collect()
+}
 
-    if let Some(max) = max_divergence {
-        eprintln!("max duration divergence: {max:?}");
+/// Whether `a` and `b` both have a [`MediaInfo::fingerprint`], and don't look alike.
+fn fingerprints_differ(a: &MediaInfo, b: &MediaInfo) -> bool {
+    let (Some(a), Some(b)) = (&a.fingerprint, &b.fingerprint) else {
+        return false;
+    };
+    if a.is_empty() || a.len() != b.len() {
+        return false;
     }
+    let distance: u32 = a.iter().zip(b).map(|(&a, &b)| hash_distance(a, b)).sum();
+    distance / a.len() as u32 > MAX_FINGERPRINT_DISTANCE
+}
 
-    Ok(groups)
+/// Max deviation allowed between the timestamps of inputs grouped by [`GroupKey::Timestamp`]
+const MAX_TIMESTAMP_DELTA: SignedDuration = SignedDuration::from_secs(5);
+
+/// Max deviation allowed between the timestamps of inputs grouped by [`GroupKey::Auto`]
+const MAX_AUTO_TIMESTAMP_DELTA: SignedDuration = SignedDuration::from_hours(24);
+
+/// Options controlling how [`group()`] identifies and groups its inputs.
+#[derive(Debug, Clone)]
+pub struct GroupOptions {
+    /// Determine the duration of each audio and video input by decoding it in full (see
+    /// [`decoded_duration()`]) rather than trusting the duration reported by its container.
+    /// Much slower, but necessary for inputs with bogus container durations.
+    pub accurate_duration: bool,
+    /// The strategy used to group inputs.
+    pub key: GroupKey,
+    /// The most inputs identified at once (see [`par_map()`]), the available parallelism by
+    /// default.
+    pub jobs: Option<usize>,
+    /// Max deviation allowed between the durations of inputs grouped by
+    /// [`GroupKey::Duration`], [`MAX_DURATION_DELTA`] by default.
+    pub max_delta: Duration,
+    /// Pair each image with a short video sharing its file name stem or timestamp (see
+    /// [`is_live_photo()`]), regardless of `key`.
+    pub live_photos: bool,
+    /// Asked to confirm each ambiguous grouping (see [`AMBIGUOUS_DURATION_DELTA`]) of an input
+    /// with a group, given the difference between their durations. Rejected inputs start a new
+    /// group.
+    pub confirm: Option<ConfirmFn>,
+    /// A directory to write the raw `ffprobe` output for each input to, as
+    /// `<file name>.ffprobe.json`.
+    pub dump_ffprobe: Option<PathBuf>,
+    /// Group inputs by [`GroupKey::Duration`] by which of these durations they're nearest
+    /// (within `max_delta`), rather than by how close they are to one another.
+    /// Inputs not near any of them form groups of their own.
+    pub duration_buckets: Vec<Duration>,
+    /// Refuse to group videos of materially different frame rates, rather than warning about
+    /// them, and fail to identify inputs not near any of the `duration_buckets`.
+    pub strict: bool,
+    /// Accept subtitle inputs, grouped by duration like audio and video (at most one per
+    /// group) and carried into the merged output.
+    pub subtitles: bool,
+    /// A directory to cache the identification of each input in, so it's only re-identified
+    /// once its size or modification time changes.
+    pub cache: Option<PathBuf>,
+    /// What inputs must have in common to be grouped, beyond their `key`.
+    pub match_by: MatchMode,
+    /// Leave images out of the groups altogether, rather than grouping each with a video of
+    /// its resolution (as its thumbnail) or passing it through on its own.
+    pub skip_images: bool,
+    /// Leave videos smaller than this (in either orientation) out of the groups altogether,
+    /// as long as there's a video at least this large of nearly the same duration to stand in
+    /// for them. See [`drop_low_resolution()`].
+    pub min_resolution: Option<Resolution>,
+    /// Group the parts of a video split into several (e.g. `_part1` and `_part2`, see
+    /// [`video_parts()`]) as one video, for the total of their durations, to be concatenated
+    /// by [`merge()`], rather than as encodes of the same resolution excluding one another.
+    pub concat_parts: bool,
+    /// The ffprobe (and ffmpeg, see `accurate_duration` and [`MatchMode::Content`]) binaries
+    /// inputs are identified with
+    pub tools: Tools,
 }
 
-pub fn merge(group: &[MediaInfo], out: &Path) -> Result<&'static str> {
-    assert!(!group.is_empty());
+impl Default for GroupOptions {
+    fn default() -> Self {
+        GroupOptions {
+            accurate_duration: false,
+            key: GroupKey::default(),
+            max_delta: MAX_DURATION_DELTA,
+            jobs: None,
+            live_photos: false,
+            confirm: None,
+            dump_ffprobe: None,
+            duration_buckets: Vec::new(),
+            strict: false,
+            subtitles: false,
+            cache: None,
+            match_by: MatchMode::Duration,
+            skip_images: false,
+            min_resolution: None,
+            concat_parts: false,
+            tools: Tools::default(),
+        }
+    }
+}
 
-    let audio = group.iter().find(|mi| mi.is_audio());
-    let video = group
-        .iter()
-        .filter(|mi| mi.is_video())
-        .max_by_key(|mi| mi.resolution);
+/// Confirms whether an input belongs in a group, see [`GroupOptions::confirm`].
+pub type ConfirmFn = fn(&MediaInfo, &[MediaInfo], Duration) -> bool;
 
-    let (Some(audio), Some(video)) = (audio, video) else {
-        // Missing either audio or video
-        eprintln!("Copying source file as-is to {}", out.display());
-        std::fs::copy(&group[0].path, out)
-            .with_context(|| format!("Error writing to destination {}", out.display()))?;
-        return Ok(if audio.is_some() { "audio" } else { "video" });
+/// Inputs grouped by duration with a larger difference than this (but still within
+/// [`GroupOptions::max_delta`]) may not belong together after all.
+pub const AMBIGUOUS_DURATION_DELTA: Duration = Duration::from_millis(500);
+
+/// The longest a video paired with an image as a live photo may be
+pub const LIVE_PHOTO_MAX_DURATION: Duration = Duration::from_secs(5);
+
+/// Max deviation allowed between the timestamps of an image and video paired as a live photo
+const LIVE_PHOTO_MAX_TIMESTAMP_DELTA: SignedDuration = SignedDuration::from_secs(1);
+
+/// Whether `image` and `video` look like the still and motion halves of a "live photo" (or
+/// burst), e.g. `IMG_1234.HEIC` and `IMG_1234.MOV`: a short video sharing the file name stem
+/// of the image, or taken at the same time.
+pub fn is_live_photo(image: &MediaInfo, video: &MediaInfo) -> bool {
+    if !image.is_image() || !video.is_video() || video.duration > LIVE_PHOTO_MAX_DURATION {
+        return false;
+    }
+
+    let stem = |mi: &MediaInfo| {
+        let name = file_name(&mi.path)?;
+        Some(
+            name.rsplit_once('.')
+                .map_or(&*name, |(stem, _)| stem)
+                .to_owned(),
+        )
+    };
+    let same_stem = match (stem(image), stem(video)) {
+        (Some(a), Some(b)) => a.eq_ignore_ascii_case(&b),
+        _ => false,
     };
 
-    let ffmpeg = Command::new("ffmpeg")
-        .arg("-hide_banner")
-        .arg("-v")
-        .arg("error")
-        .arg("-i")
-        .arg(&audio.path)
-        .arg("-i")
-        .arg(&video.path)
-        .arg("-c")
-        .arg("copy")
-        .arg("-f")
-        .arg("mp4")
-        .arg(out)
-        .output()
-        .context("Error running ffmpeg!")?;
+    same_stem
+        || image.timestamp.duration_since(video.timestamp).abs() <= LIVE_PHOTO_MAX_TIMESTAMP_DELTA
+}
 
-    if !ffmpeg.status.success() {
-        let mut stderr = std::io::stderr().lock();
-        let _ = stderr.write_all(&ffmpeg.stderr);
-        bail!("Error merging media");
+/// The name (without extension) `path` shares with the other parts of a video split into
+/// parts, and its part number, if it's named like one, e.g. `("reel_1234", 2)` for
+/// `reel_1234_part2.mp4` (or `-part2`, `.part2`, or ` Part 2`).
+fn part_number(path: &Path) -> Option<(String, u32)> {
+    let name = file_name(path)?;
+    let stem = name.rsplit_once('.').map_or(&*name, |(stem, _)| stem);
+    let idx = stem.to_ascii_lowercase().rfind("part")?;
+    let (base, number) = (&stem[..idx], stem[idx + "part".len()..].trim_start());
+    let base = base.strip_suffix(['_', '-', '.', ' '])?;
+    if base.is_empty() || number.is_empty() || !number.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
     }
+    Some((base.to_owned(), number.parse().ok()?))
+}
 
-    let fname = out.file_name().unwrap();
-    eprintln!("Merged audio and video into {}", fname.display());
+#[test]
+fn part_numbers() {
+    let part = |path: &str| part_number(Path::new(path));
+
+    assert_eq!(
+        part("dir/reel_1234_part2.mp4"),
+        Some(("reel_1234".to_owned(), 2))
+    );
+    assert_eq!(part("Trip - Part 10.mov"), Some(("Trip -".to_owned(), 10)));
+    assert_eq!(part("clip.part1"), None);
+    assert_eq!(part("reel_1234_party.mp4"), None);
+    assert_eq!(part("part1.mp4"), None);
+    assert_eq!(part("reel_1234_720p.mp4"), None);
+}
+
+/// The parts of the video `video` is a part of in `group`, in order, i.e. the videos of the
+/// same resolution named like it but for their [`part_number()`], or else just `video` itself.
+pub fn video_parts<'a>(group: &'a [MediaInfo], video: &'a MediaInfo) -> Vec<&'a MediaInfo> {
+    let Some((base, _)) = part_number(&video.path) else {
+        return vec![video];
+    };
+
+    let mut parts: Vec<_> = group
+        .iter()
+        .filter(|mi| mi.is_video() && mi.resolution == video.resolution)
+        .filter_map(|mi| match part_number(&mi.path) {
+            Some((other, n)) if other == base => Some((n, mi)),
+            _ => None,
+        })
+        .collect();
+    parts.sort_by_key(|&(n, mi)| (n, mi.timestamp));
+    // Duplicates of a part are encodes of the same content
+    parts.dedup_by_key(|&mut (n, _)| n);
+    parts.into_iter().map(|(_, mi)| mi).collect()
+}
 
-    Ok("audio+video")
+/// How long the attachment merged from `group` is: as long as its longest member, or its
+/// longest video of several parts (see [`video_parts()`]) once they're concatenated.
+pub fn merged_duration(group: &[MediaInfo]) -> Duration {
+    group
+        .iter()
+        .map(|mi| match mi.is_video() {
+            true => video_parts(group, mi)
+                .iter()
+                .map(|part| part.duration)
+                .sum(),
+            false => mi.duration,
+        })
+        .max()
+        .unwrap_or_default()
 }
 
-pub fn thumbnail(src: &Path, out: &Path) -> Result<()> {
-    let mi = identify(src).context("Error identifying file to screenshot")?;
+/// Takes the later parts of each video split into parts (see [`video_parts()`]) out of
+/// `media_info`, standing in for the whole video with its first part for the total duration
+/// of its parts. Returns each first part as it was, along with the parts taken out, to be put
+/// back into the group the first part joins with [`restore_parts()`].
+fn take_parts(media_info: &mut Vec<MediaInfo>) -> Vec<(MediaInfo, Vec<MediaInfo>)> {
+    let mut chains = Vec::new();
+    let mut taken = Vec::new();
+    for video in media_info.iter().filter(|mi| mi.is_video()) {
+        if taken.contains(&video.path) {
+            continue;
+        }
+        let parts = video_parts(media_info, video);
+        if parts.len() < 2 {
+            continue;
+        }
+        taken.extend(parts.iter().map(|part| part.path.clone()));
+        chains.push(parts.into_iter().cloned().collect::<Vec<_>>());
+    }
 
-    if mi.is_audio() && mi.stream_count == 1 {
-        let mut file = File::create(out).with_context(|| {
-            format!("Error creating screenshot output file at {}", out.display())
-        })?;
-        file.write_all(audio_only_png())
-            .with_context(|| format!("Error writing screenshot to {}", out.display()))?;
-        return Ok(());
+    let mut taken_out = Vec::new();
+    for parts in chains {
+        let (first, rest) = parts.split_first().unwrap();
+        media_info.retain(|mi| !rest.contains(mi));
+        let whole = media_info.iter_mut().find(|mi| *mi == first).unwrap();
+        whole.duration = parts.iter().map(|part| part.duration).sum();
+        taken_out.push((first.clone(), rest.to_vec()));
+    }
+    taken_out
+}
+
+/// Puts the parts taken out of the inputs by [`take_parts()`] back into `groups`, alongside
+/// their first part.
+fn restore_parts(groups: &mut [Vec<MediaInfo>], parts: Vec<(MediaInfo, Vec<MediaInfo>)>) {
+    for (first, rest) in parts {
+        let group = groups.iter_mut().find(|group| {
+            group
+                .iter()
+                .any(|mi| mi.is_video() && mi.path == first.path)
+        });
+        if let Some(group) = group {
+            let whole = group.iter_mut().find(|mi| mi.path == first.path).unwrap();
+            *whole = first;
+            group.extend(rest);
+        }
     }
+}
+
+#[test]
+fn group_concat_parts() {
+    let part1 = media_info("live_1_part1.mp4", MediaType::Video, 60.0);
+    let part2 = media_info("live_1_part2.mp4", MediaType::Video, 30.0);
+    let audio = media_info("live_1_audio.mp4", MediaType::Audio, 90.2);
+    let inputs = vec![part2.clone(), audio.clone(), part1.clone()];
+
+    // Parts of the same resolution are otherwise mutually exclusive, and far from the audio's
+    // duration
+    let groups = group_media(inputs.clone(), &Default::default(), &Warnings::quiet());
+    assert_eq!(groups.len(), 3);
+
+    let concat = GroupOptions {
+        concat_parts: true,
+        ..Default::default()
+    };
+    let groups = group_media(inputs, &concat, &Warnings::quiet());
+    assert_eq!(groups, [vec![audio, part1.clone(), part2.clone()]]);
+    assert_eq!(video_parts(&groups[0], &part2), [&part1, &part2]);
+    assert_eq!(merged_duration(&groups[0]), Duration::from_secs_f64(90.2));
+    assert_eq!(merged_duration(&groups[0][1..]), Duration::from_secs(90));
 
-    let play_overlay = {
-        let mut path = std::env::temp_dir();
-        path.push(Uuid::now_v7().to_string());
-        path.set_extension("png");
-        path
+    let webm = |path: &str, secs| MediaInfo {
+        codec: "vp9".to_owned(),
+        ..media_info(path, MediaType::Video, secs)
     };
+    let parts = [
+        webm("live_1_part1.webm", 60.0),
+        webm("live_1_part2.webm", 30.0),
+    ];
+    let opts = MergeOptions::default();
+    assert_eq!(output_extension(&parts, &opts), "mp4");
+    assert_eq!(output_extension(&parts[..1], &opts), "webm");
+}
+
+/// The `ffconcat` script concatenating the files at `paths` with ffmpeg's concat demuxer.
+fn concat_script<'a>(paths: impl IntoIterator<Item = &'a Path>) -> String {
+    let mut script = "ffconcat version 1.0\n".to_owned();
+    for path in paths {
+        // Relative paths would be resolved relative to the script
+        let path = match is_url(path) {
+            true => path.to_owned(),
+            false => std::fs::canonicalize(path).unwrap_or_else(|_| path.to_owned()),
+        };
+        let path = path.to_string_lossy().replace('\'', r"'\''");
+        script.push_str(&format!("file '{path}'\n"));
+    }
+    script
+}
 
-    File::create(&play_overlay)
-        .and_then(|mut f| f.write_all(play_overlay_webp()))
-        .with_context(|| format!("Error writing play overlay to {}", play_overlay.display()))?;
+#[test]
+fn concat_scripts() {
+    let script = concat_script([
+        Path::new("https://cdn.example.com/part1.mp4"),
+        Path::new("https://cdn.example.com/it's part2.mp4"),
+    ]);
+    assert_eq!(
+        script,
+        "ffconcat version 1.0\n\
+         file 'https://cdn.example.com/part1.mp4'\n\
+         file 'https://cdn.example.com/it'\\''s part2.mp4'\n"
+    );
+}
 
-    defer! {
-        if let Err(err) = std::fs::remove_file(&play_overlay) {
-            eprintln!("Error cleaning up play overlay icon at {}: {err}", play_overlay.display());
+/// Identifies `path` the way [`group()`] does with `opts`.
+pub fn identify_with(path: &Path, opts: &GroupOptions, warnings: &Warnings) -> Result<MediaInfo> {
+    let cache = opts.cache.as_deref().filter(|_| !is_url(path));
+    let mi = match cache.and_then(|dir| load_cached(dir, path, opts)) {
+        Some(mi) => mi,
+        None => {
+            let mi = probe_media(path, opts, warnings)?;
+            if let Some(dir) = cache
+                && let Err(err) = store_cached(dir, path, opts, &mi)
+            {
+                warnings.warn(
+                    "cache",
+                    Some(path),
+                    format!(
+                        "Error caching {} in {}: {err:#}",
+                        path.display(),
+                        dir.display()
+                    ),
+                );
+            }
+            mi
         }
+    };
+
+    if mi.is_subtitle() && !opts.subtitles {
+        bail!(
+            "{}: subtitle inputs are only accepted with subtitles enabled",
+            path.display()
+        );
     }
+    let unbucketed = !opts.duration_buckets.is_empty()
+        && !mi.is_image()
+        && duration_bucket(mi.duration, opts).is_none();
+    if opts.strict && unbucketed {
+        bail!(
+            "{}: duration of {:?} isn't within {:?} of any duration bucket",
+            path.display(),
+            mi.duration,
+            opts.max_delta
+        );
+    }
+    Ok(mi)
+}
+
+/// A [`MediaInfo`] cached in [`GroupOptions::cache`], valid only as long as the file it
+/// describes keeps the same size and modification time.
+#[derive(Serialize, Deserialize)]
+struct CacheEntry {
+    path: PathBuf,
+    size: u64,
+    mtime: Timestamp,
+    /// Whether the duration was decoded, see [`GroupOptions::accurate_duration`]
+    accurate_duration: bool,
+    info: MediaInfo,
+}
+
+/// Where the [`CacheEntry`] of `path` is kept in cache directory `dir`.
+fn cache_path(dir: &Path, path: &Path) -> PathBuf {
+    let path = std::fs::canonicalize(path).unwrap_or_else(|_| path.to_owned());
+    dir.join(format!(
+        "{}.json",
+        fnv_hash([path.as_os_str().as_encoded_bytes()])
+    ))
+}
+
+/// The size and modification time of the file at `path`.
+fn cache_key(path: &Path) -> Result<(u64, Timestamp)> {
+    let md = path.metadata()?;
+    Ok((md.len(), md.modified()?.try_into()?))
+}
+
+/// The [`MediaInfo`] of `path` cached in `dir`, unless it's missing or stale.
+fn load_cached(dir: &Path, path: &Path, opts: &GroupOptions) -> Option<MediaInfo> {
+    let json = std::fs::read(cache_path(dir, path)).ok()?;
+    let entry: CacheEntry = serde_json::from_slice(&json).ok()?;
+    let (size, mtime) = cache_key(path).ok()?;
+    // Videos identified without fingerprinting them have yet to be fingerprinted
+    let fingerprinted = opts.match_by == MatchMode::Duration
+        || !entry.info.is_video()
+        || entry.info.fingerprint.is_some();
+    let fresh = entry.size == size
+        && entry.mtime == mtime
+        && entry.accurate_duration == opts.accurate_duration
+        && fingerprinted;
+    fresh.then(|| MediaInfo {
+        // The same file may be named by a different (e.g. relative) path this time
+        path: path.to_owned(),
+        ..entry.info
+    })
+}
 
-    let start = match mi.duration.as_secs() {
-        ..1 => "0",
-        ..6 => "2.0",
-        _ => "5.0",
+/// Caches `mi`, the [`MediaInfo`] of `path`, in `dir`.
+fn store_cached(dir: &Path, path: &Path, opts: &GroupOptions, mi: &MediaInfo) -> Result<()> {
+    let (size, mtime) = cache_key(path)?;
+    let entry = CacheEntry {
+        path: path.to_owned(),
+        size,
+        mtime,
+        accurate_duration: opts.accurate_duration,
+        info: mi.clone(),
     };
+    std::fs::write(cache_path(dir, path), serde_json::to_vec(&entry)?)?;
+    Ok(())
+}
 
-    #[allow(unused)]
-    enum FfVersion {
-        /// For compatibility with FFmpeg 6.0 (and older versions we don't officially support)
-        ///
-        /// Runs on even the latest versions of FFmpeg, but generates a deprecation warning on
-        /// FFmpeg 7 or later.
-        Compat,
-        /// Only supports FFmpeg 7+
-        Ffmpeg7,
+#[test]
+fn cache_invalidation() {
+    let dir = std::env::temp_dir().join(format!("instagrouper-cache-{}", Uuid::now_v7()));
+    std::fs::create_dir(&dir).unwrap();
+    let path = dir.join("clip.m4a");
+    std::fs::write(&path, b"audio").unwrap();
+    let mi = MediaInfo {
+        path: path.clone(),
+        codec: "aac".to_owned(),
+        size: 5,
+        ..media_info("", MediaType::Audio, 3.0)
+    };
+    let opts = GroupOptions::default();
+    store_cached(&dir, &path, &opts, &mi).unwrap();
+    let hit = load_cached(&dir, &path, &opts);
+    let accurate = GroupOptions {
+        accurate_duration: true,
+        ..Default::default()
+    };
+    let other_opts = load_cached(&dir, &path, &accurate);
+    std::fs::write(&path, b"longer audio").unwrap();
+    let resized = load_cached(&dir, &path, &opts);
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(hit, Some(mi));
+    assert_eq!(other_opts, None);
+    assert_eq!(resized, None);
+}
+
+/// Identifies `path` with ffprobe (and, for [`GroupOptions::accurate_duration`], ffmpeg).
+fn probe_media(path: &Path, opts: &GroupOptions, warnings: &Warnings) -> Result<MediaInfo> {
+    let json = probe(path, &opts.tools)
+        .with_context(|| format!("Error identifying {}", path.display()))?;
+    if let Some(dir) = &opts.dump_ffprobe {
+        let name = file_name(path).unwrap_or_else(|| "input".to_owned());
+        let dump = dir.join(format!("{name}.ffprobe.json"));
+        if let Err(err) = std::fs::write(&dump, &json) {
+            warnings.warn(
+                "ffprobe-dump",
+                Some(path),
+                format!("Error writing {}: {err}", dump.display()),
+            );
+        }
+    }
+    let mut mi = parse_ffprobe(path, &json)
+        .with_context(|| format!("Error identifying {}", path.display()))?;
+    if opts.accurate_duration && !mi.is_image() && !mi.is_subtitle() {
+        let duration = decoded_duration(&mi, &opts.tools)
+            .with_context(|| format!("Error decoding {}", path.display()))?;
+        if duration.abs_diff(mi.duration) > Duration::from_millis(100) {
+            warnings.warn(
+                "inaccurate-duration",
+                Some(path),
+                format!(
+                    "{}: container reports a duration of {:?} but decodes to {duration:?}",
+                    path.display(),
+                    mi.duration
+                ),
+            );
+        }
+        mi.duration = duration;
+    }
+    if opts.match_by == MatchMode::Content && mi.is_video() {
+        match fingerprint(&mi, &opts.tools) {
+            Ok(fingerprint) => mi.fingerprint = Some(fingerprint),
+            Err(err) => warnings.warn(
+                "fingerprint",
+                Some(path),
+                format!(
+                    "Error fingerprinting {}, matching it by duration alone: {err:#}",
+                    path.display()
+                ),
+            ),
+        }
     }
+    Ok(mi)
+}
 
-    fn ffmpeg_vf(version: FfVersion) -> &'static str {
-        match version {
-            FfVersion::Compat => {
-                // [1:v][0:v] takes (1) logo and (2) video.
-                // 'main_w' and 'main_h' refer to the second input ([0:v] / the video).
-                // It outputs two streams: [logo] (the scaled icon) and [video] (the original
-                // video). We then overlay [logo] on [video].
+/// The one of the [`GroupOptions::duration_buckets`] nearest `duration`, if within
+/// [`GroupOptions::max_delta`] of it.
+fn duration_bucket(duration: Duration, opts: &GroupOptions) -> Option<Duration> {
+    opts.duration_buckets
+        .iter()
+        .copied()
+        .min_by_key(|bucket| bucket.abs_diff(duration))
+        .filter(|bucket| bucket.abs_diff(duration) <= opts.max_delta)
+}
+
+/// Group paths into files belonging to the same attachment, with the default
+/// [`GroupOptions`]. Inputs holding no media (see [`BadMedia`]) are skipped with a warning.
+pub fn group<P: AsRef<Path> + Sync>(paths: &[P]) -> Result<Vec<Vec<MediaInfo>>> {
+    group_with(paths, &GroupOptions::default())
+}
+
+/// Like [`group()`], but grouping as configured by `opts`.
+pub fn group_with<P: AsRef<Path> + Sync>(
+    paths: &[P],
+    opts: &GroupOptions,
+) -> Result<Vec<Vec<MediaInfo>>> {
+    group_with_progress(paths, opts, &Warnings::default(), None)
+}
+
+/// Like [`group_with()`], but collecting the warnings raised in `warnings` and reporting each
+/// input identified (or skipped) to `progress` as [`Event::Identified`] (or [`Event::Error`]),
+/// followed by [`Event::Grouped`].
+pub fn group_with_progress<P: AsRef<Path> + Sync>(
+    paths: &[P],
+    opts: &GroupOptions,
+    warnings: &Warnings,
+    progress: Option<Progress>,
+) -> Result<Vec<Vec<MediaInfo>>> {
+    let report = |event: Event<'_>| {
+        if let Some(progress) = progress {
+            progress(event);
+        }
+    };
+
+    // Each input is identified by its own ffprobe process, so they're best identified at once
+    let media_info = par_map(paths, opts.jobs, |_, path| {
+        let path = path.as_ref();
+        match identify_with(path, opts, warnings) {
+            Err(err) if err.downcast_ref::<BadMedia>().is_some() => {
+                warnings.warn("bad-media", Some(path), format!("Skipping {err:#}"));
+                report(Event::Error(path, &err));
+                Ok(None)
+            }
+            Ok(mi) => {
+                report(Event::Identified(path));
+                Ok(Some(mi))
+            }
+            Err(err) => Err(err),
+        }
+    });
+    let media_info = media_info
+        .into_iter()
+        .filter_map(Result::transpose)
+        .collect::<Result<_>>()?;
+
+    let groups = group_media(media_info, opts, warnings);
+    report(Event::Grouped(groups.len()));
+    Ok(groups)
+}
+
+/// Group inputs already identified (e.g. by an earlier [`identify()`], cached to disk) into
+/// files belonging to the same attachment, the way [`group()`] does without re-running ffprobe.
+pub fn group_identified(
+    media_info: Vec<MediaInfo>,
+    opts: &GroupOptions,
+    warnings: &Warnings,
+) -> Vec<Vec<MediaInfo>> {
+    group_media(media_info, opts, warnings)
+}
+
+/// Maps `f` over `items` in parallel on up to `jobs` threads (the available parallelism by
+/// default), preserving order. `f` also receives the item's index.
+pub fn par_map<T, R, F>(items: &[T], jobs: Option<usize>, f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(usize, &T) -> R + Sync,
+{
+    if items.is_empty() {
+        return Vec::new();
+    }
+
+    let jobs = jobs.unwrap_or_else(|| {
+        std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
+    });
+    let chunk_size = items.len().div_ceil(jobs.max(1));
+
+    std::thread::scope(|s| {
+        items
+            .chunks(chunk_size)
+            .enumerate()
+            .map(|(chunk_idx, chunk)| {
+                let f = &f;
+                s.spawn(move || {
+                    chunk
+                        .iter()
+                        .enumerate()
+                        .map(|(in_chunk_idx, item)| f(chunk_idx * chunk_size + in_chunk_idx, item))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect()
+    })
+}
+
+#[test]
+fn par_map_order() {
+    let items: Vec<usize> = (0..10).collect();
+    for jobs in [None, Some(1), Some(3), Some(64)] {
+        let mapped = par_map(&items, jobs, |i, item| (i, item * 2));
+        assert_eq!(
+            mapped,
+            items.iter().map(|&i| (i, i * 2)).collect::<Vec<_>>()
+        );
+    }
+}
+
+/// The outcome of [`group_and_report()`].
+#[derive(Debug)]
+pub struct GroupReport {
+    pub groups: Vec<Vec<MediaInfo>>,
+    /// The largest difference in duration between the audio and video inputs of any one group
+    pub max_divergence: Duration,
+    /// Everything [`group()`] would have logged
+    pub warnings: Vec<Warning>,
+}
+
+/// Like [`group_with()`], but returns the grouping diagnostics instead of printing them.
+pub fn group_and_report<P: AsRef<Path> + Sync>(
+    paths: &[P],
+    opts: &GroupOptions,
+) -> Result<GroupReport> {
+    let warnings = Warnings::quiet();
+    let groups = group_with_progress(paths, opts, &warnings, None)?;
+    Ok(GroupReport {
+        max_divergence: max_divergence(&groups),
+        groups,
+        warnings: warnings.into_vec(),
+    })
+}
+
+/// Groups already identified inputs into files belonging to the same attachment, see
+/// [`group()`].
+pub fn group_media(
+    mut media_info: Vec<MediaInfo>,
+    opts: &GroupOptions,
+    warnings: &Warnings,
+) -> Vec<Vec<MediaInfo>> {
+    if opts.skip_images {
+        media_info.retain(|mi| !mi.is_image());
+    }
+    if let Some(min) = opts.min_resolution {
+        drop_low_resolution(&mut media_info, min, opts.max_delta, warnings);
+    }
+    // Each video split into parts is grouped as a whole, by its first part
+    let parts = match opts.concat_parts {
+        true => take_parts(&mut media_info),
+        false => Vec::new(),
+    };
+
+    // Sort by duration to ensure we process similar files together first
+    media_info.sort_by_key(|mi| std::cmp::Reverse(mi.duration));
+
+    let mut groups: Vec<Vec<MediaInfo>> = Vec::new();
+
+    let prefixes: Vec<_> = media_info.iter().map(|mi| name_prefix(&mi.path)).collect();
+    let key = opts.key.resolve(prefixes.iter().all(Option::is_some));
+    let mut group_prefixes: Vec<Option<String>> = Vec::new();
+
+    for (mi, prefix) in media_info.into_iter().zip(prefixes) {
+        let mut best_match: Option<(usize, Duration)> = None; // (idx, Duration)
+
+        // Images are grouped last (as they have no duration), with their live photo videos
+        // already grouped.
+        let live_photo = groups
+            .iter()
+            .position(|group| opts.live_photos && live_photo_partner(&mi, group).is_some());
+        if let Some(idx) = live_photo {
+            groups[idx].push(mi);
+            continue;
+        }
+
+        for (idx, group) in groups.iter().enumerate() {
+            let Ok(delta) = affinity(key, opts, (&mi, &prefix), (group, &group_prefixes[idx]))
+            else {
+                continue;
+            };
+
+            // Take closest match
+            match best_match {
+                None => best_match = Some((idx, delta)),
+                Some((_, best_delta)) => {
+                    if delta < best_delta {
+                        best_match = Some((idx, delta));
+                    }
+                }
+            }
+        }
+
+        let best_match = best_match.filter(|&(idx, delta)| {
+            let ambiguous = key == GroupKey::Duration
+                && opts.duration_buckets.is_empty()
+                && !mi.is_image()
+                && delta > AMBIGUOUS_DURATION_DELTA;
+            match opts.confirm {
+                Some(confirm) if ambiguous => confirm(&mi, &groups[idx], delta),
+                _ => true,
+            }
+        });
+
+        if let Some((idx, _)) = best_match {
+            groups[idx].push(mi);
+        } else {
+            // No compatible group found, create a new one
+            groups.push(vec![mi]);
+            group_prefixes.push(prefix);
+        }
+    }
+
+    // Subtitles are only carried into an attachment alongside its audio or video
+    groups.retain(|group| match group.as_slice() {
+        [subtitle] if subtitle.is_subtitle() => {
+            warnings.warn(
+                "unmatched-subtitle",
+                Some(&subtitle.path),
+                format!(
+                    "{} matched no audio or video, ignoring it",
+                    subtitle.path.display()
+                ),
+            );
+            false
+        }
+        _ => true,
+    });
+
+    for group in &groups {
+        let mut videos = group.iter().filter(|mi| mi.is_video());
+        let Some(first) = videos.next() else {
+            continue;
+        };
+        if let Some(other) = videos.find(|other| frame_rates_differ(first, other)) {
+            warnings.warn(
+                "frame-rate-mismatch",
+                Some(&other.path),
+                format!(
+                    "{} ({} fps) and {} ({} fps) were grouped despite their frame rates \
+                     differing, they may not be the same content",
+                    first.path.display(),
+                    first.frame_rate.unwrap(),
+                    other.path.display(),
+                    other.frame_rate.unwrap()
+                ),
+            );
+        }
+    }
+
+    let max = max_divergence(&groups);
+    if !max.is_zero() {
+        warnings.warn(
+            "duration-divergence",
+            None,
+            format!(
+                "max duration divergence: {max:?} (of {:?} tolerated)",
+                opts.max_delta
+            ),
+        );
+    }
+
+    restore_parts(&mut groups, parts);
+    groups
+}
+
+/// The largest difference in duration between the non-image members of any of `groups`.
+fn max_divergence(groups: &[Vec<MediaInfo>]) -> Duration {
+    groups
+        .iter()
+        .map(|g| {
+            let mut candidates = g.iter().filter(|mi| !mi.is_image());
+            // Members are inserted by decreasing duration
+            let first = candidates.next();
+            let last = candidates.next_back();
+            if let (Some(first), Some(last)) = (first, last) {
+                first.duration - last.duration
+            } else {
+                // Less than two non-image files in group
+                Duration::ZERO
+            }
+        })
+        .max()
+        .unwrap_or_default()
+}
+
+/// Whether `a` and `b` are both tagged, with different languages.
+fn distinct_languages(a: &MediaInfo, b: &MediaInfo) -> bool {
+    match (&a.language, &b.language) {
+        (Some(a), Some(b)) => !a.eq_ignore_ascii_case(b),
+        _ => false,
+    }
+}
+
+/// The video in `group` forming a live photo with `image`, if `group` doesn't already have an
+/// image.
+fn live_photo_partner<'a>(image: &MediaInfo, group: &'a [MediaInfo]) -> Option<&'a MediaInfo> {
+    if group.iter().any(MediaInfo::is_image) {
+        return None;
+    }
+    group.iter().find(|video| is_live_photo(image, video))
+}
+
+/// How closely `mi` (named with `prefix`) matches `group` (named with `group_prefix`) when
+/// grouping by `key`, the closest match being the one `mi` joins. Fails with the reason `mi`
+/// can't join `group` at all.
+fn affinity(
+    key: GroupKey,
+    opts: &GroupOptions,
+    (mi, prefix): (&MediaInfo, &Option<String>),
+    (group, group_prefix): (&[MediaInfo], &Option<String>),
+) -> std::result::Result<Duration, String> {
+    // If audio/image, group must not already have audio/image.
+    // If video, group must not already have this resolution.
+    let conflict = group.iter().find(|other| match mi.media {
+        // Audio in different languages are alternatives to choose from when merging
+        MediaType::Audio => other.is_audio() && !distinct_languages(mi, other),
+        MediaType::Image => other.is_image(),
+        MediaType::Video => other.resolution == mi.resolution,
+        MediaType::Subtitle => other.is_subtitle(),
+    });
+    if let Some(other) = conflict {
+        return Err(match mi.media {
+            MediaType::Video => format!(
+                "{} is also a video of the same resolution",
+                other.path.display()
+            ),
+            MediaType::Audio => format!("{} is also audio", other.path.display()),
+            MediaType::Image => format!("{} is also an image", other.path.display()),
+            MediaType::Subtitle => format!("{} is also a subtitle", other.path.display()),
+        });
+    }
+
+    if mi.is_video()
+        && let Some(other) = group
+            .iter()
+            .find(|other| other.is_video() && aspect_ratios_differ(mi, other))
+    {
+        return Err(format!(
+            "{} is a video of a different aspect ratio, {} rather than {}",
+            other.path.display(),
+            other.resolution.unwrap(),
+            mi.resolution.unwrap()
+        ));
+    }
+
+    if opts.strict
+        && mi.is_video()
+        && let Some(other) = group
+            .iter()
+            .find(|other| other.is_video() && frame_rates_differ(mi, other))
+    {
+        return Err(format!(
+            "{} is a video at {} fps rather than {} fps",
+            other.path.display(),
+            other.frame_rate.unwrap(),
+            mi.frame_rate.unwrap()
+        ));
+    }
+
+    if opts.match_by == MatchMode::Content
+        && let Some(other) = group.iter().find(|other| fingerprints_differ(mi, other))
+    {
+        return Err(format!(
+            "{} doesn't look like the same content",
+            other.path.display()
+        ));
+    }
+
+    let time_delta = mi.timestamp.duration_since(group[0].timestamp).abs();
+    if opts.key == GroupKey::Auto && time_delta > MAX_AUTO_TIMESTAMP_DELTA {
+        return Err(format!(
+            "timestamps are {time_delta:#} apart, more than {MAX_AUTO_TIMESTAMP_DELTA:#}"
+        ));
+    }
+
+    let duration_delta = mi.duration.abs_diff(group[0].duration);
+    match key {
+        GroupKey::Prefix if prefix.is_none() || prefix != group_prefix => Err(format!(
+            "file name prefixes {prefix:?} and {group_prefix:?} differ"
+        )),
+        GroupKey::Timestamp if time_delta > MAX_TIMESTAMP_DELTA => Err(format!(
+            "timestamps are {time_delta:#} apart, more than {MAX_TIMESTAMP_DELTA:#}"
+        )),
+        GroupKey::Timestamp => Ok(time_delta.unsigned_abs()),
+        // Hack to skip thumbnails: images join the first group with a video of the same
+        // resolution.
+        GroupKey::Duration if mi.is_image() => {
+            if group.iter().any(|other| other.resolution == mi.resolution) {
+                Ok(Duration::ZERO)
+            } else {
+                Err("no video of the same resolution as the image".to_owned())
+            }
+        }
+        _ if mi.is_image() => Ok(Duration::ZERO),
+        GroupKey::Duration if !opts.duration_buckets.is_empty() => {
+            let in_bucket = |mi: &MediaInfo| {
+                duration_bucket(mi.duration, opts).ok_or_else(|| {
+                    format!(
+                        "{} is {:?} long, not near any duration bucket",
+                        mi.path.display(),
+                        mi.duration
+                    )
+                })
+            };
+            match (in_bucket(mi)?, in_bucket(&group[0])?) {
+                (bucket, group_bucket) if bucket == group_bucket => Ok(duration_delta),
+                (bucket, group_bucket) => Err(format!(
+                    "durations are nearest different buckets, {bucket:?} and {group_bucket:?}"
+                )),
+            }
+        }
+        GroupKey::Duration if duration_delta > opts.max_delta => Err(format!(
+            "durations differ by {duration_delta:?}, more than {:?}",
+            opts.max_delta
+        )),
+        _ => Ok(duration_delta),
+    }
+}
+
+/// The outcome of [`compare()`]ing two inputs.
+#[derive(Debug, Clone)]
+pub struct Comparison {
+    /// The [`GroupKey`] the inputs were compared by, resolved if [`GroupKey::Auto`]
+    pub key: GroupKey,
+    pub duration_delta: Duration,
+    pub timestamp_delta: SignedDuration,
+    pub prefixes: [Option<String>; 2],
+    /// Whether the inputs would be grouped together, were they the only inputs
+    pub grouped: bool,
+    /// The deciding factor for the verdict
+    pub reason: String,
+}
+
+/// Explains whether [`group()`] would group `a` and `b` together, were they the only inputs.
+pub fn compare(a: &MediaInfo, b: &MediaInfo, opts: &GroupOptions) -> Comparison {
+    let prefixes = [name_prefix(&a.path), name_prefix(&b.path)];
+    let key = opts.key.resolve(prefixes.iter().all(Option::is_some));
+    // Inputs are grouped by decreasing duration
+    let (first, second) = if b.duration > a.duration {
+        (1, 0)
+    } else {
+        (0, 1)
+    };
+    let inputs = [a, b];
+
+    let (mi, group) = (inputs[second], std::slice::from_ref(inputs[first]));
+    let live_photo = opts.live_photos && live_photo_partner(mi, group).is_some();
+    let verdict = if live_photo {
+        Ok(Duration::ZERO)
+    } else {
+        affinity(
+            key,
+            opts,
+            (mi, &prefixes[second]),
+            (group, &prefixes[first]),
+        )
+    };
+    let reason = match (&verdict, key) {
+        (Err(reason), _) => reason.clone(),
+        (Ok(_), _) if live_photo => "the image and video form a live photo".to_owned(),
+        (Ok(_), _) if inputs[second].is_image() && key == GroupKey::Duration => {
+            "the image has the same resolution as the video".to_owned()
+        }
+        (Ok(_), GroupKey::Prefix) => "file name prefixes match".to_owned(),
+        (Ok(_), GroupKey::Timestamp) => {
+            format!("timestamps are within {MAX_TIMESTAMP_DELTA:#}")
+        }
+        (Ok(_), _) => format!("durations are within {:?}", opts.max_delta),
+    };
+
+    Comparison {
+        key,
+        duration_delta: inputs[0].duration.abs_diff(inputs[1].duration),
+        timestamp_delta: inputs[1].timestamp.duration_since(inputs[0].timestamp),
+        prefixes,
+        grouped: verdict.is_ok(),
+        reason,
+    }
+}
+
+#[test]
+fn compare_verdicts() {
+    let video = media_info("reel_1_720.mp4", MediaType::Video, 10.0);
+    let opts = |key| GroupOptions {
+        key,
+        ..Default::default()
+    };
+
+    let audio = media_info("reel_1_audio.mp4", MediaType::Audio, 10.5);
+    assert!(compare(&video, &audio, &opts(GroupKey::Duration)).grouped);
+    let audio = media_info("reel_2_audio.mp4", MediaType::Audio, 10.5);
+    assert!(!compare(&video, &audio, &opts(GroupKey::Prefix)).grouped);
+    let audio = media_info("reel_1_audio.mp4", MediaType::Audio, 12.0);
+    assert!(!compare(&video, &audio, &opts(GroupKey::Duration)).grouped);
+    assert!(compare(&video, &audio, &opts(GroupKey::Auto)).grouped);
+    assert_eq!(
+        compare(&video, &audio, &opts(GroupKey::Auto)).key,
+        GroupKey::Prefix
+    );
+    assert!(!compare(&video, &video, &opts(GroupKey::Timestamp)).grouped);
+
+    let still = media_info("IMG_1234.HEIC", MediaType::Image, 0.0);
+    let live = GroupOptions {
+        live_photos: true,
+        ..Default::default()
+    };
+    let motion = media_info("IMG_1234.MOV", MediaType::Video, 3.0);
+    assert!(!compare(&motion, &still, &opts(GroupKey::Duration)).grouped);
+    assert!(compare(&motion, &still, &live).grouped);
+    let motion = media_info("IMG_1234.MOV", MediaType::Video, 10.0);
+    assert!(!compare(&motion, &still, &live).grouped);
+
+    let fps = |rate: &str, height| MediaInfo {
+        frame_rate: Some(rate.parse().unwrap()),
+        resolution: Some(Resolution {
+            width: height * 16 / 9,
+            height,
+        }),
+        ..media_info("reel_1_720.mp4", MediaType::Video, 10.0)
+    };
+    let strict = GroupOptions {
+        strict: true,
+        ..Default::default()
+    };
+    let (hd, sd) = (fps("30000/1001", 720), fps("30/1", 480));
+    assert!(compare(&hd, &sd, &strict).grouped);
+    let sd = fps("60/1", 480);
+    assert!(compare(&hd, &sd, &opts(GroupKey::Duration)).grouped);
+    assert!(!compare(&hd, &sd, &strict).grouped);
+
+    let buckets = GroupOptions {
+        duration_buckets: vec![Duration::from_secs(15), Duration::from_secs(30)],
+        ..Default::default()
+    };
+    let clip = |secs| media_info("clip.mp4", MediaType::Audio, secs);
+    let video_at = |secs| media_info("clip_720.mp4", MediaType::Video, secs);
+    // Grouped despite differing by more than max_delta
+    assert!(compare(&clip(15.6), &video_at(14.7), &buckets).grouped);
+    assert!(!compare(&clip(15.6), &video_at(15.9), &buckets).grouped);
+    assert!(!compare(&clip(10.0), &video_at(10.0), &buckets).grouped);
+
+    let content = GroupOptions {
+        match_by: MatchMode::Content,
+        ..Default::default()
+    };
+    let looking = |fingerprint: Option<Vec<u64>>, height| MediaInfo {
+        fingerprint,
+        ..fps("30/1", height)
+    };
+    let (hd, sd) = (
+        looking(Some(vec![0, u64::MAX]), 720),
+        looking(Some(vec![0xff, u64::MAX]), 480),
+    );
+    assert!(compare(&hd, &sd, &content).grouped);
+    let other = looking(Some(vec![u64::MAX, 0]), 480);
+    assert!(compare(&hd, &other, &opts(GroupKey::Duration)).grouped);
+    assert!(!compare(&hd, &other, &content).grouped);
+    // Falls back to duration without a fingerprint
+    assert!(compare(&hd, &looking(None, 480), &content).grouped);
+    assert!(
+        compare(
+            &hd,
+            &media_info("reel_1_audio.mp4", MediaType::Audio, 10.0),
+            &content
+        )
+        .grouped
+    );
+
+    let shaped = |path: &str, width, height| MediaInfo {
+        resolution: Some(Resolution { width, height }),
+        ..media_info(path, MediaType::Video, 10.0)
+    };
+    let (landscape, portrait) = (
+        shaped("reel_1_1080.mp4", 1920, 1080),
+        shaped("reel_1_1920.mp4", 1080, 1920),
+    );
+    assert!(!compare(&landscape, &portrait, &opts(GroupKey::Duration)).grouped);
+    assert!(!compare(&landscape, &portrait, &opts(GroupKey::Prefix)).grouped);
+    let groups = group_media(
+        vec![landscape.clone(), portrait.clone()],
+        &opts(GroupKey::Duration),
+        &Warnings::quiet(),
+    );
+    assert_eq!(groups, [vec![landscape.clone()], vec![portrait]]);
+    // Encodes rounded to even dimensions are still the same shape
+    let sd = shaped("reel_1_480.mp4", 854, 480);
+    assert!(compare(&landscape, &sd, &opts(GroupKey::Duration)).grouped);
+}
+
+#[test]
+fn group_subtitles() {
+    let inputs = vec![
+        media_info("reel.mp4", MediaType::Video, 10.0),
+        media_info("reel.m4a", MediaType::Audio, 10.2),
+        media_info("reel.vtt", MediaType::Subtitle, 9.8),
+        media_info("reel.srt", MediaType::Subtitle, 9.9),
+        media_info("other.vtt", MediaType::Subtitle, 30.0),
+    ];
+    let opts = GroupOptions {
+        key: GroupKey::Duration,
+        subtitles: true,
+        ..Default::default()
+    };
+    let warnings = Warnings::quiet();
+    let groups = group_media(inputs, &opts, &warnings);
+
+    // At most one subtitle per group, the rest on their own and so dropped
+    assert_eq!(groups.len(), 1);
+    assert_eq!(groups[0].len(), 3);
+    assert_eq!(groups[0].iter().filter(|mi| mi.is_subtitle()).count(), 1);
+    let warnings = warnings.into_vec();
+    let unmatched = warnings.iter().filter(|w| w.code == "unmatched-subtitle");
+    assert_eq!(unmatched.count(), 2);
+    assert_eq!(
+        output_extension(&groups[0], &MergeOptions::default()),
+        "mp4"
+    );
+}
+
+#[test]
+fn group_skip_images() {
+    let inputs = vec![
+        media_info("reel.mp4", MediaType::Video, 10.0),
+        media_info("reel.jpg", MediaType::Image, 0.0),
+        media_info("other.jpg", MediaType::Image, 0.0),
+    ];
+    let skip_images = GroupOptions {
+        skip_images: true,
+        ..Default::default()
+    };
+    let groups = group_media(inputs.clone(), &Default::default(), &Warnings::quiet());
+    assert_eq!(groups.concat().len(), 3);
+    let groups = group_media(inputs, &skip_images, &Warnings::quiet());
+    assert_eq!(
+        groups,
+        [vec![media_info("reel.mp4", MediaType::Video, 10.0)]]
+    );
+}
+
+/// Whether `resolution` is smaller than `min` in either dimension, comparing the longer and
+/// shorter edges of each so e.g. a 720x1280 portrait video meets a minimum of 1280x720.
+fn below_resolution(resolution: Resolution, min: Resolution) -> bool {
+    let edges = |res: Resolution| (res.width.max(res.height), res.width.min(res.height));
+    let ((long, short), (min_long, min_short)) = (edges(resolution), edges(min));
+    long < min_long || short < min_short
+}
+
+/// Drops the videos of `media_info` below the `min` resolution, e.g. the tiny preview encodes
+/// shipped alongside the full resolution video, so they aren't grouped at all. A video is only
+/// dropped if another video meeting `min` is within `max_delta` of its duration, so the only
+/// video of an attachment is never dropped. Videos of unknown resolution are kept.
+fn drop_low_resolution(
+    media_info: &mut Vec<MediaInfo>,
+    min: Resolution,
+    max_delta: Duration,
+    warnings: &Warnings,
+) {
+    let is_low = |mi: &MediaInfo| {
+        mi.is_video() && mi.resolution.is_some_and(|res| below_resolution(res, min))
+    };
+    let kept: Vec<Duration> = media_info
+        .iter()
+        .filter(|mi| mi.is_video() && !is_low(mi))
+        .map(|mi| mi.duration)
+        .collect();
+
+    media_info.retain(|mi| {
+        let replaced = is_low(mi)
+            && kept
+                .iter()
+                .any(|&duration| duration.abs_diff(mi.duration) <= max_delta);
+        if replaced {
+            warnings.warn(
+                "low-resolution",
+                Some(&mi.path),
+                format!(
+                    "Skipping {}: {} is below the minimum resolution of {min}",
+                    mi.path.display(),
+                    mi.resolution.unwrap()
+                ),
+            );
+        }
+        !replaced
+    });
+}
+
+#[test]
+fn group_min_resolution() {
+    let input = |path: &str, media, width, height, secs| MediaInfo {
+        resolution: (media == MediaType::Video).then_some(Resolution { width, height }),
+        ..media_info(path, media, secs)
+    };
+    let opts = GroupOptions {
+        min_resolution: Some("1280x720".parse().unwrap()),
+        ..Default::default()
+    };
+    let best = input("reel_1_1080.mp4", MediaType::Video, 1080, 1920, 10.0);
+    let preview = input("reel_1_240.mp4", MediaType::Video, 136, 240, 10.2);
+    let audio = input("reel_1_audio.mp4", MediaType::Audio, 0, 0, 10.1);
+    // The only video of its attachment, even if small
+    let lone = input("reel_2_240.mp4", MediaType::Video, 240, 136, 30.0);
+
+    let warnings = Warnings::quiet();
+    let groups = group_media(
+        vec![best.clone(), preview, audio.clone(), lone.clone()],
+        &opts,
+        &warnings,
+    );
+    assert_eq!(groups, [vec![lone], vec![audio, best]]);
+    let skipped: Vec<_> = warnings
+        .into_vec()
+        .into_iter()
+        .filter(|warning| warning.code == "low-resolution")
+        .collect();
+    assert_eq!(skipped.len(), 1);
+    assert!(skipped[0].message.contains("reel_1_240.mp4"));
+}
+
+/// A stable identifier for `group`, derived from the paths of its members.
+///
+/// The identifier doesn't depend on the order of the members or on the platform or version
+/// of Rust, so it may be used to recognize the same group across runs.
+pub fn group_id(group: &[MediaInfo]) -> String {
+    let mut paths: Vec<_> = group.iter().map(|mi| mi.path.as_os_str()).collect();
+    paths.sort();
+    fnv_hash(paths.into_iter().map(OsStr::as_encoded_bytes))
+}
+
+/// The 64-bit FNV-1a hash of `parts` (each terminated by a NUL byte), in hex.
+fn fnv_hash<'a>(parts: impl IntoIterator<Item = &'a [u8]>) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for part in parts {
+        for &b in part.iter().chain(&[0]) {
+            hash ^= b as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    format!("{hash:016x}")
+}
+
+/// The role an input plays in the attachment it's grouped into, see [`classify()`].
+pub type Role = MediaType;
+
+/// The role `mi` plays in the attachment [`merge()`] produces from its group: its audio, its
+/// video, its image (e.g. a poster), or its subtitles.
+pub fn classify(mi: &MediaInfo) -> Role {
+    mi.media.clone()
+}
+
+/// The video [`merge()`] picks from `group`, i.e. the one of the highest resolution (and then
+/// frame rate).
+pub fn best_video(group: &[MediaInfo]) -> Option<&MediaInfo> {
+    let fps = |mi: &MediaInfo| mi.frame_rate.map_or(0.0, |rate| rate.fps());
+    group
+        .iter()
+        .filter(|mi| classify(mi) == Role::Video)
+        .max_by(|a, b| {
+            a.resolution
+                .cmp(&b.resolution)
+                .then(fps(a).total_cmp(&fps(b)))
+        })
+}
+
+/// The audio [`merge()`] picks from `group` absent any [`MergeOptions::audio_langs`], i.e. the
+/// one of the highest [`effective_bit_rate()`] (the first of several with the same, or no,
+/// bit rate).
+pub fn best_audio(group: &[MediaInfo]) -> Option<&MediaInfo> {
+    best_audio_of(group.iter().filter(|mi| classify(mi) == Role::Audio))
+}
+
+/// The one of `audio` of the highest [`effective_bit_rate()`], see [`best_audio()`].
+fn best_audio_of<'a>(audio: impl Iterator<Item = &'a MediaInfo>) -> Option<&'a MediaInfo> {
+    audio.fold(None, |best: Option<&MediaInfo>, mi| match best {
+        Some(best) if effective_bit_rate(best) >= effective_bit_rate(mi) => Some(best),
+        _ => Some(mi),
+    })
+}
+
+/// The bit rate of `mi` as reported by ffprobe, or else as estimated from its size and
+/// duration (which overestimates it by whatever else is in the file, e.g. cover art).
+fn effective_bit_rate(mi: &MediaInfo) -> Option<u64> {
+    if let Some(bit_rate) = mi.bit_rate {
+        return Some(bit_rate.into());
+    }
+    let secs = mi.duration.as_secs_f64();
+    (secs > 0.0 && mi.size > 0).then(|| (mi.size as f64 * 8.0 / secs) as u64)
+}
+
+/// Selects the audio and video streams [`merge()`] combines from `group`.
+///
+/// The audio is the [`best_audio()`] in the first of the preferred
+/// [`MergeOptions::audio_langs`] available, or else the best of all.
+fn select_streams<'a>(
+    group: &'a [MediaInfo],
+    opts: &MergeOptions,
+) -> (Option<&'a MediaInfo>, Option<&'a MediaInfo>) {
+    let preferred = opts.audio_langs.iter().find_map(|lang| {
+        best_audio_of(group.iter().filter(|mi| {
+            mi.is_audio()
+                && mi
+                    .language
+                    .as_deref()
+                    .is_some_and(|l| language_matches(l, lang))
+        }))
+    });
+    let audio = preferred.or_else(|| best_audio(group));
+
+    (audio, best_video(group))
+}
+
+/// The subtitles [`merge()`] carries into its output from `group`, if it has audio or video
+/// to carry them alongside.
+fn select_subtitle<'a>(group: &'a [MediaInfo], opts: &MergeOptions) -> Option<&'a MediaInfo> {
+    let (audio, video) = select_streams(group, opts);
+    group
+        .iter()
+        .find(|mi| mi.is_subtitle())
+        .filter(|_| audio.is_some() || video.is_some())
+}
+
+/// Options controlling how [`merge()`] produces its output.
+#[derive(Debug, Clone, Default)]
+pub struct MergeOptions {
+    /// The pixel format the output video must use (e.g. `yuv420p` for 8-bit output compatible
+    /// with most devices). Video in any other pixel format is re-encoded, as a stream copy
+    /// can't convert it.
+    pub pix_fmt: Option<String>,
+    /// Preserve the offset between the start times of the audio and video (as reported by
+    /// their containers) when merging, instead of starting both at zero.
+    pub sync: bool,
+    /// The languages to prefer the audio of, in order of preference (see
+    /// [`language_matches()`])
+    pub audio_langs: Vec<String>,
+    /// Trim black frames off the start and end of the video (see [`black_trim()`]), which
+    /// requires re-encoding it.
+    pub trim_black: bool,
+    /// When to re-encode streams rather than stream-copying them into the output.
+    pub reencode: Reencode,
+    /// Overwrite an existing output, see [`refuse_overwrite()`]
+    pub overwrite: bool,
+    /// The container merged outputs are written in
+    pub container: Container,
+    /// The most the duration of a merged output may differ from that of the longest stream
+    /// merged into it (see [`verify_duration()`]), unchecked if `None`.
+    pub verify_duration: Option<Duration>,
+    /// Fail merges whose output fails [`verify_duration()`], rather than warning about them.
+    pub strict: bool,
+    /// The ffmpeg and ffprobe binaries outputs are merged and verified with
+    pub tools: Tools,
+}
+
+/// Fails if `out` already exists with any content, unless `overwrite`. Empty files are taken to
+/// be outputs reserved ahead of time (e.g. so concurrent runs don't pick the same names), and
+/// are always written to.
+pub fn refuse_overwrite(out: &Path, overwrite: bool) -> Result<()> {
+    if !overwrite && out.metadata().is_ok_and(|md| md.len() > 0) {
+        bail!("Refusing to overwrite existing {}", out.display());
+    }
+    Ok(())
+}
+
+#[test]
+fn refuse_overwrites() {
+    let out = std::env::temp_dir().join(format!("{}.mp4", Uuid::now_v7()));
+    assert!(refuse_overwrite(&out, false).is_ok());
+    // Reserved outputs are written to
+    File::create(&out).unwrap();
+    assert!(refuse_overwrite(&out, false).is_ok());
+    std::fs::write(&out, b"merged").unwrap();
+    let refused = refuse_overwrite(&out, false);
+    let forced = refuse_overwrite(&out, true);
+    std::fs::remove_file(&out).unwrap();
+    assert!(refused.is_err());
+    assert!(forced.is_ok());
+}
+
+/// When [`merge()`] re-encodes streams rather than stream-copying them, regardless of
+/// [`MergeOptions::pix_fmt`] and [`MergeOptions::trim_black`], which always re-encode.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Reencode {
+    /// Never, even if the output won't play
+    Never,
+    /// When the codec of a stream isn't supported by the [`Container`] (e.g. Opus or VP8 in an
+    /// MP4), to its [`audio_encoder()`](Container::audio_encoder) or
+    /// [`video_encoder()`](Container::video_encoder)
+    #[default]
+    Auto,
+    /// Always, even streams copied as-is otherwise
+    Always,
+}
+
+impl Reencode {
+    /// Whether the audio or video stream of `mi` is re-encoded when merged into `container`.
+    fn applies(self, mi: &MediaInfo, container: Container) -> bool {
+        match self {
+            Reencode::Never => false,
+            Reencode::Always => true,
+            Reencode::Auto => !container.holds(&mi.codec),
+        }
+    }
+}
+
+/// The container format [`merge()`] writes its output in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Container {
+    #[default]
+    Mp4,
+    /// Matroska, which holds streams of any codec
+    Mkv,
+    /// The subset of Matroska holding only VP8/VP9/AV1 video and Opus/Vorbis audio
+    Webm,
+}
+
+impl Container {
+    /// The extension of outputs in this container, e.g. `mp4`
+    pub fn extension(self) -> &'static str {
+        match self {
+            Container::Mp4 => "mp4",
+            Container::Mkv => "mkv",
+            Container::Webm => "webm",
+        }
+    }
+
+    /// The name of the ffmpeg muxer of this container, as passed to `-f`
+    fn muxer(self) -> &'static str {
+        match self {
+            Container::Mp4 => "mp4",
+            Container::Mkv => "matroska",
+            Container::Webm => "webm",
+        }
+    }
+
+    /// Whether streams of `codec` may be stream-copied into this container (and reliably
+    /// played back).
+    fn holds(self, codec: &str) -> bool {
+        match self {
+            Container::Mp4 => mp4_codec(codec),
+            Container::Mkv => true,
+            Container::Webm => matches!(codec, "vp8" | "vp9" | "av1" | "opus" | "vorbis"),
+        }
+    }
+
+    /// The ffmpeg encoder (and its name) audio is re-encoded with for this container
+    fn audio_encoder(self) -> (&'static str, &'static str) {
+        match self {
+            Container::Mp4 | Container::Mkv => ("aac", "AAC"),
+            Container::Webm => ("libopus", "Opus"),
+        }
+    }
+
+    /// The ffmpeg encoder (and its name) video is re-encoded with for this container
+    fn video_encoder(self) -> (&'static str, &'static str) {
+        match self {
+            Container::Mp4 | Container::Mkv => ("libx264", "H.264"),
+            Container::Webm => ("libvpx-vp9", "VP9"),
+        }
+    }
+
+    /// The ffmpeg encoder subtitles are converted with for this container, if they can't be
+    /// copied as-is
+    fn subtitle_encoder(self) -> Option<&'static str> {
+        match self {
+            Container::Mp4 => Some("mov_text"),
+            Container::Mkv => None,
+            Container::Webm => Some("webvtt"),
+        }
+    }
+}
+
+impl FromStr for Container {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "mp4" => Ok(Container::Mp4),
+            "mkv" | "matroska" => Ok(Container::Mkv),
+            "webm" => Ok(Container::Webm),
+            _ => bail!("Invalid container {s}, expected mp4, mkv, or webm"),
+        }
+    }
+}
+
+#[test]
+fn container_codecs() {
+    let opus = |reencode: Reencode, container| {
+        let mi = MediaInfo {
+            codec: "opus".to_owned(),
+            ..media_info("audio.webm", MediaType::Audio, 0.0)
+        };
+        reencode.applies(&mi, container)
+    };
+    assert!(opus(Reencode::Auto, Container::Mp4));
+    assert!(!opus(Reencode::Auto, Container::Mkv));
+    assert!(!opus(Reencode::Auto, Container::Webm));
+    assert!(opus(Reencode::Always, Container::Webm));
+    assert!(!Container::Webm.holds("h264"));
+    assert_eq!("MKV".parse::<Container>().unwrap(), Container::Mkv);
+    assert!("avi".parse::<Container>().is_err());
+}
+
+/// Whether streams of `codec` may be stream-copied into an MP4 (and reliably played back).
+fn mp4_codec(codec: &str) -> bool {
+    matches!(
+        codec,
+        "aac" | "mp3" | "ac3" | "eac3" | "alac" | "h264" | "hevc" | "av1" | "vp9" | "mpeg4"
+    )
+}
+
+/// The kind of attachment [`merge()`] makes of a group, see [`merge_kind()`]. Serialized as
+/// `audio+video`, `audio`, `video`, or `image`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MergeKind {
+    AudioVideo,
+    Audio,
+    Video,
+    Image,
+}
+
+impl MergeKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MergeKind::AudioVideo => "audio+video",
+            MergeKind::Audio => "audio",
+            MergeKind::Video => "video",
+            MergeKind::Image => "image",
+        }
+    }
+}
+
+impl Display for MergeKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for MergeKind {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+/// What [`merge()`] made of a group.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Merged {
+    pub kind: MergeKind,
+    /// Whether the source was copied as-is (for lack of both audio and video to merge)
+    pub copied: bool,
+    /// Whether any stream was re-encoded rather than copied
+    pub reencoded: bool,
+    /// The resolution of the selected video, if any
+    pub resolution: Option<Resolution>,
+}
+
+/// Whether stream language `tag` is the `wanted` language, compared case-insensitively. A
+/// shorter `wanted` matches any tag it's a prefix of, e.g. `en` matches `eng` and `en-US`.
+pub fn language_matches(tag: &str, wanted: &str) -> bool {
+    tag.len() >= wanted.len()
+        && tag.is_char_boundary(wanted.len())
+        && tag[..wanted.len()].eq_ignore_ascii_case(wanted)
+}
+
+impl MergeOptions {
+    /// Whether `video` can't be stream-copied as-is and must be re-encoded.
+    fn must_reencode(&self, video: &MediaInfo) -> bool {
+        self.pix_fmt
+            .as_ref()
+            .is_some_and(|pix_fmt| video.pix_fmt.as_ref() != Some(pix_fmt))
+    }
+}
+
+/// Whether `path` is actually the URL of a remote input, which ffprobe and ffmpeg can read
+/// directly, rather than a local path.
+pub fn is_url(path: &Path) -> bool {
+    let Some(path) = path.to_str() else {
+        return false;
+    };
+    ["http://", "https://", "rtmp://", "rtmps://"]
+        .iter()
+        .any(|scheme| {
+            path.get(..scheme.len())
+                .is_some_and(|prefix| prefix.eq_ignore_ascii_case(scheme))
+        })
+}
+
+/// The file name of local path or URL `path`, excluding any URL query string or fragment.
+pub fn file_name(path: &Path) -> Option<String> {
+    if !is_url(path) {
+        return path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned());
+    }
+
+    let url = path.to_str().unwrap();
+    let url = url.split(['?', '#']).next().unwrap();
+    let (_, name) = url.split_once("://").unwrap().1.rsplit_once('/')?;
+    (!name.is_empty()).then(|| name.to_owned())
+}
+
+/// The file name of `path` up to its second `_`, e.g. `reel_1234` for `reel_1234_720p.mp4`,
+/// if it has one.
+pub fn name_prefix(path: &Path) -> Option<String> {
+    let name = file_name(path)?;
+    let (idx, _) = name.match_indices('_').nth(1)?;
+    Some(name[..idx].to_owned())
+}
+
+#[test]
+fn audio_language_preference() {
+    assert!(language_matches("eng", "en"));
+    assert!(language_matches("en-US", "EN"));
+    assert!(language_matches("spa", "spa"));
+    assert!(!language_matches("en", "eng"));
+    assert!(!language_matches("ger", "de"));
+}
+
+#[test]
+fn file_name_prefix() {
+    let prefix = |path: &str| name_prefix(Path::new(path));
+
+    assert_eq!(
+        prefix("dir/reel_1234_720p.mp4").as_deref(),
+        Some("reel_1234")
+    );
+    assert_eq!(
+        prefix("reel_1234_audio_en.m4a").as_deref(),
+        Some("reel_1234")
+    );
+    assert_eq!(prefix("reel_1234.mp4"), None);
+}
+
+#[test]
+fn url_file_name() {
+    let name = |path: &str| file_name(Path::new(path));
+
+    assert_eq!(name("dir/clip_1.mp4").as_deref(), Some("clip_1.mp4"));
+    assert_eq!(
+        name("https://cdn.example.com/v/clip_1.mp4?sig=a/b#t=1").as_deref(),
+        Some("clip_1.mp4")
+    );
+    assert_eq!(name("HTTP://cdn.example.com/").as_deref(), None);
+    assert_eq!(name("rtmp://live.example.com").as_deref(), None);
+}
+
+/// A template for the names (without extension) of merged attachments, e.g. the default
+/// `{stub}_{index}`, with the placeholders:
+///
+/// - `{stub}`: the [`name_prefix()`] of the first input (or a fresh UUID)
+/// - `{index}`: the number of the attachment, zero-padded to three digits
+/// - `{date}`: the (UTC) date of the earliest input, e.g. `2024-05-01`
+/// - `{resolution}`: the resolution of the video, e.g. `1280x720`, if there is one
+/// - `{duration}`: the duration in whole seconds, e.g. `15s`
+///
+/// and `{{` and `}}` for literal braces. `{index}` is required to tell attachments apart.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NameTemplate(Vec<NamePart>);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum NamePart {
+    Literal(String),
+    Stub,
+    Index,
+    Date,
+    Resolution,
+    Duration,
+}
+
+impl NameTemplate {
+    pub const DEFAULT: &str = "{stub}_{index}";
+
+    /// Whether the template includes the `{resolution}`, i.e. tells apart the names of
+    /// variants of a group that only differ by it.
+    pub fn has_resolution(&self) -> bool {
+        self.0.contains(&NamePart::Resolution)
+    }
+
+    /// The name of the `index`th attachment, merged from `group`.
+    pub fn render(&self, stub: &str, index: usize, group: &[MediaInfo]) -> String {
+        let mut name = String::new();
+        for part in &self.0 {
+            match part {
+                NamePart::Literal(literal) => name.push_str(literal),
+                NamePart::Stub => name.push_str(stub),
+                NamePart::Index => name.push_str(&format!("{index:0>3}")),
+                NamePart::Date => {
+                    if let Some(timestamp) = group.iter().map(|mi| mi.timestamp).min() {
+                        name.push_str(&timestamp.strftime("%Y-%m-%d").to_string());
+                    }
+                }
+                NamePart::Resolution => {
+                    if let Some(resolution) = best_video(group).and_then(|mi| mi.resolution) {
+                        name.push_str(&resolution.to_string());
+                    }
+                }
+                NamePart::Duration => {
+                    let duration = group.iter().map(|mi| mi.duration).max().unwrap_or_default();
+                    name.push_str(&format!("{}s", duration.as_secs()));
+                }
+            }
+        }
+        name
+    }
+
+    /// Whether `stem` could have been rendered from this template, taking any placeholder to
+    /// stand for any text but `{stub}` (which is never empty) and `{index}` (three digits).
+    pub fn matches(&self, stem: &str) -> bool {
+        fn matches(parts: &[NamePart], s: &str) -> bool {
+            let Some((part, rest)) = parts.split_first() else {
+                return s.is_empty();
+            };
+            match part {
+                NamePart::Literal(literal) => s
+                    .strip_prefix(literal.as_str())
+                    .is_some_and(|s| matches(rest, s)),
+                NamePart::Index => {
+                    s.len() >= 3
+                        && s.as_bytes()[..3].iter().all(u8::is_ascii_digit)
+                        && matches(rest, &s[3..])
+                }
+                _ => {
+                    let min = usize::from(*part == NamePart::Stub);
+                    (min..=s.len())
+                        .filter(|&i| s.is_char_boundary(i))
+                        .any(|i| matches(rest, &s[i..]))
+                }
+            }
+        }
+        matches(&self.0, stem)
+    }
+}
+
+impl Default for NameTemplate {
+    fn default() -> Self {
+        NameTemplate::DEFAULT.parse().unwrap()
+    }
+}
+
+impl FromStr for NameTemplate {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let Some((name, rest)) = chars.as_str().split_once('}') else {
+                        bail!("Unclosed {{ in name template {s}");
+                    };
+                    let part = match name {
+                        "stub" => NamePart::Stub,
+                        "index" => NamePart::Index,
+                        "date" => NamePart::Date,
+                        "resolution" => NamePart::Resolution,
+                        "duration" => NamePart::Duration,
+                        other => bail!(
+                            "Unknown placeholder {{{other}}} in name template {s}, expected \
+                             {{stub}}, {{index}}, {{date}}, {{resolution}}, or {{duration}}"
+                        ),
+                    };
+                    if !literal.is_empty() {
+                        parts.push(NamePart::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(part);
+                    chars = rest.chars();
+                }
+                '}' => bail!("Unmatched }} in name template {s}"),
+                '/' | '\\' => bail!("Name template {s} must not contain path separators"),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(NamePart::Literal(literal));
+        }
+        if !parts.contains(&NamePart::Index) {
+            bail!("Name template {s} must include {{index}} to tell attachments apart");
+        }
+        Ok(NameTemplate(parts))
+    }
+}
+
+#[test]
+fn name_template() {
+    let mut video = MediaInfo {
+        codec: "h264".to_owned(),
+        size: 1,
+        timestamp: "2024-05-01T23:30:00Z".parse().unwrap(),
+        ..media_info("reel_1234_720p.mp4", MediaType::Video, 15.4)
+    };
+    let render = |template: &str, group: &[MediaInfo]| {
+        let template: NameTemplate = template.parse().unwrap();
+        template.render("reel_1234", 7, group)
+    };
+
+    assert_eq!(
+        render(NameTemplate::DEFAULT, &[video.clone()]),
+        "reel_1234_007"
+    );
+    assert_eq!(
+        render("{date}_{index}_{resolution}_{duration}", &[video.clone()]),
+        "2024-05-01_007_1280x720_15s"
+    );
+    assert_eq!(render("{{{index}}}", &[video.clone()]), "{007}");
+    video.media = MediaType::Audio;
+    video.resolution = None;
+    assert_eq!(
+        render("{stub}-{resolution}{index}", &[video]),
+        "reel_1234-007"
+    );
+
+    assert!("{stub}_{n}".parse::<NameTemplate>().is_err());
+    assert!("{stub}_{index".parse::<NameTemplate>().is_err());
+    assert!("{stub}}_{index}".parse::<NameTemplate>().is_err());
+    assert!("{stub}".parse::<NameTemplate>().is_err());
+    assert!("out/{index}".parse::<NameTemplate>().is_err());
+}
+
+#[test]
+fn name_template_matches() {
+    let default = NameTemplate::default();
+    assert!(default.matches("reel_1234_001"));
+    assert!(!default.matches("reel_1234"));
+    assert!(!default.matches("reel_1234_720p"));
+    assert!(!default.matches("_001"));
+
+    let dated: NameTemplate = "{date}-{index} ({resolution})".parse().unwrap();
+    assert!(dated.matches("2024-05-01-012 (1280x720)"));
+    assert!(dated.matches("-012 ()"));
+    assert!(!dated.matches("2024-05-01-012"));
+}
+
+/// The extension (without the leading `.`) of the file [`merge()`] produces for `group`.
+///
+/// Audio and video are remuxed into the [`MergeOptions::container`] (an MP4 by default), but a
+/// group missing either is copied as-is and must keep the extension of its source so e.g. a
+/// lone `.mp3` or `.wav` isn't passed off as an `.mp4`.
+pub fn output_extension(group: &[MediaInfo], opts: &MergeOptions) -> String {
+    assert!(!group.is_empty());
+
+    let remuxed = opts.container.extension().to_owned();
+    let (audio, video) = select_streams(group, opts);
+    match (audio, video) {
+        (Some(_), Some(_)) => return remuxed,
+        (None, Some(video)) if opts.must_reencode(video) || opts.trim_black => return remuxed,
+        // Parts are concatenated rather than copied as-is
+        (_, Some(video)) if video_parts(group, video).len() > 1 => return remuxed,
+        (Some(mi), None) | (None, Some(mi))
+            if mi.fragmented
+                || opts.reencode == Reencode::Always
+                || select_subtitle(group, opts).is_some() =>
+        {
+            return remuxed;
+        }
+        _ => (),
+    }
+
+    file_name(&representative(group, opts).path)
+        .and_then(|name| {
+            name.rsplit_once('.')
+                .map(|(_, ext)| ext.to_ascii_lowercase())
+        })
+        .filter(|ext| !ext.is_empty())
+        .unwrap_or_else(|| "mp4".to_owned())
+}
+
+/// The input of `group` that stands for it when it's copied as-is by [`merge()`], by role:
+/// its video if it has one, otherwise its audio, otherwise its (first) image.
+fn representative<'a>(group: &'a [MediaInfo], opts: &MergeOptions) -> &'a MediaInfo {
+    let (audio, video) = select_streams(group, opts);
+    video.or(audio).unwrap_or_else(|| {
+        let image = group.iter().find(|mi| mi.is_image());
+        image.unwrap_or(&group[0])
+    })
+}
+
+#[test]
+fn representative_by_role() {
+    let input = |path: &str, media| media_info(path, media, 0.0);
+    let opts = MergeOptions::default();
+    let image = input("poster.png", MediaType::Image);
+    let audio = input("audio.m4a", MediaType::Audio);
+    let video = input("video.webm", MediaType::Video);
+
+    let group = [image.clone()];
+    assert_eq!(output_extension(&group, &opts), "png");
+    assert_eq!(merge_kind(&group), MergeKind::Image);
+    let group = [image.clone(), audio];
+    assert_eq!(output_extension(&group, &opts), "m4a");
+    assert_eq!(merge_kind(&group), MergeKind::Audio);
+    let group = [image, video];
+    assert_eq!(output_extension(&group, &opts), "webm");
+    assert_eq!(merge_kind(&group), MergeKind::Video);
+    assert_eq!(
+        serde_json::to_string(&MergeKind::AudioVideo).unwrap(),
+        r#""audio+video""#
+    );
+}
+
+#[test]
+fn reencode_always_copies_images() {
+    let dir = std::env::temp_dir().join(format!("instagrouper-merge-{}", Uuid::now_v7()));
+    std::fs::create_dir(&dir).unwrap();
+    let image = MediaInfo {
+        path: dir.join("poster.png"),
+        ..media_info("", MediaType::Image, 0.0)
+    };
+    std::fs::write(&image.path, b"png").unwrap();
+    let opts = MergeOptions {
+        reencode: Reencode::Always,
+        ..Default::default()
+    };
+
+    let group = [image];
+    assert_eq!(output_extension(&group, &opts), "png");
+    let out = dir.join("out.png");
+    let merged = merge(&group, &out, &opts, &Warnings::quiet());
+    let copied = std::fs::read(&out);
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(merged.unwrap().copied);
+    assert_eq!(copied.unwrap(), b"png");
+}
+
+#[test]
+fn best_streams() {
+    let input = |path: &str, media, height, bit_rate| MediaInfo {
+        resolution: (height > 0).then_some(Resolution {
+            width: height * 16 / 9,
+            height,
+        }),
+        bit_rate,
+        ..media_info(path, media, 0.0)
+    };
+    let group = [
+        input("audio_64k.m4a", MediaType::Audio, 0, Some(64_000)),
+        input("video_1080.mp4", MediaType::Video, 1080, Some(5_000_000)),
+        input("audio_128k.m4a", MediaType::Audio, 0, Some(128_000)),
+        input("audio_128k_dub.m4a", MediaType::Audio, 0, Some(128_000)),
+        input("video_720.mp4", MediaType::Video, 720, Some(8_000_000)),
+        input("poster.png", MediaType::Image, 1080, None),
+    ];
+
+    assert_eq!(classify(&group[5]), Role::Image);
+    assert_eq!(best_audio(&group), Some(&group[2]));
+    assert_eq!(best_video(&group), Some(&group[1]));
+    assert_eq!(best_video(&group[2..4]), None);
+
+    // Of the same resolution, the higher frame rate is preferred
+    let at = |rate: &str| MediaInfo {
+        frame_rate: Some(rate.parse().unwrap()),
+        ..input("video_1080.mp4", MediaType::Video, 1080, None)
+    };
+    let group = [at("60/1"), at("30/1")];
+    assert_eq!(best_video(&group), Some(&group[0]));
+
+    // The best audio in the most preferred language available, or else the best of all
+    let tagged = |path, lang: &str, bit_rate| MediaInfo {
+        language: Some(lang.to_owned()),
+        ..input(path, MediaType::Audio, 0, Some(bit_rate))
+    };
+    let group = [
+        tagged("audio_en_64k.m4a", "eng", 64_000),
+        tagged("audio_es_256k.m4a", "spa", 256_000),
+        tagged("audio_en_128k.m4a", "eng", 128_000),
+    ];
+    let langs = |langs: &[&str]| MergeOptions {
+        audio_langs: langs.iter().map(|&lang| lang.to_owned()).collect(),
+        ..Default::default()
+    };
+    assert_eq!(select_streams(&group, &langs(&["en"])).0, Some(&group[2]));
+    assert_eq!(
+        select_streams(&group, &langs(&["fr", "es"])).0,
+        Some(&group[1])
+    );
+    assert_eq!(select_streams(&group, &langs(&["fr"])).0, Some(&group[1]));
+
+    // Unknown bit rates are estimated from the size of the file
+    let sized = |path, size| MediaInfo {
+        size,
+        duration: Duration::from_secs(10),
+        ..input(path, MediaType::Audio, 0, None)
+    };
+    let group = [
+        sized("audio_small.m4a", 80_000),
+        sized("audio_large.m4a", 160_000),
+        input("audio_96k.m4a", MediaType::Audio, 0, Some(96_000)),
+    ];
+    assert_eq!(best_audio(&group), Some(&group[1]));
+    assert_eq!(best_audio(&group[..1]), Some(&group[0]));
+}
+
+/// The kind of attachment [`merge()`] makes of `group`.
+pub fn merge_kind(group: &[MediaInfo]) -> MergeKind {
+    let has = |media| group.iter().any(|mi| mi.media == media);
+    match (has(MediaType::Audio), has(MediaType::Video)) {
+        (true, true) => MergeKind::AudioVideo,
+        (true, false) => MergeKind::Audio,
+        (false, true) => MergeKind::Video,
+        (false, false) => MergeKind::Image,
+    }
+}
+
+pub fn merge(
+    group: &[MediaInfo],
+    out: &Path,
+    opts: &MergeOptions,
+    warnings: &Warnings,
+) -> Result<Merged> {
+    merge_with_progress(group, out, opts, warnings, None)
+}
+
+/// Like [`merge()`], but also reports the progress of (long) merges by ffmpeg to `progress` as
+/// [`Event::Merging`], and logs it at the debug level.
+pub fn merge_with_progress(
+    group: &[MediaInfo],
+    out: &Path,
+    opts: &MergeOptions,
+    warnings: &Warnings,
+    progress: Option<Progress>,
+) -> Result<Merged> {
+    assert!(!group.is_empty());
+    refuse_overwrite(out, opts.overwrite)?;
+
+    let (audio, video) = select_streams(group, opts);
+    let subtitle = select_subtitle(group, opts);
+    let reencode = video.is_some_and(|video| opts.must_reencode(video));
+    let fragmented = [audio, video].into_iter().flatten().any(|mi| mi.fragmented);
+    // A video split into parts (grouped with `GroupOptions::concat_parts`) is concatenated
+    let parts = video
+        .map(|video| video_parts(group, video))
+        .filter(|parts| parts.len() > 1);
+    let trim = match video {
+        Some(video) if opts.trim_black && parts.is_none() => {
+            let segments =
+                black_segments(video, &opts.tools).context("Error detecting black frames")?;
+            Some(black_trim(&segments, video.duration))
+        }
+        _ => None,
+    };
+
+    if (audio.is_none() || video.is_none())
+        && !reencode
+        && !fragmented
+        && trim.is_none()
+        && subtitle.is_none()
+        && parts.is_none()
+        // Without audio or video (e.g. a lone image), there's nothing to re-encode
+        && (opts.reencode != Reencode::Always || (audio.is_none() && video.is_none()))
+    {
+        // Missing either audio or video
+        let source = &representative(group, opts).path;
+        warnings.warn(
+            "copied-as-is",
+            Some(source),
+            format!("Copying source file as-is to {}", out.display()),
+        );
+        if is_url(source) {
+            download(source, out, &opts.tools)?;
+        } else {
+            std::fs::copy(source, out)
+                .with_context(|| format!("Error writing to destination {}", out.display()))?;
+        }
+        return Ok(Merged {
+            kind: merge_kind(group),
+            copied: true,
+            reencoded: false,
+            resolution: video.and_then(|video| video.resolution),
+        });
+    }
+
+    // Streams re-encoded to be playable from an MP4, other than the video re-encoded anyway
+    let container = opts.container;
+    let reencode_audio = audio.filter(|audio| opts.reencode.applies(audio, container));
+    let reencode_video = video
+        .filter(|video| opts.reencode.applies(video, container))
+        .filter(|_| !reencode && trim.is_none());
+    if container == Container::Webm
+        && let Some(mi) = [audio, video]
+            .into_iter()
+            .flatten()
+            .filter(|mi| !opts.reencode.applies(mi, container))
+            .find(|mi| !container.holds(&mi.codec))
+        && !(mi.is_video() && (reencode || trim.is_some()))
+    {
+        bail!(
+            "{} is {}, which WebM can't hold without re-encoding it",
+            mi.path.display(),
+            mi.codec
+        );
+    }
+
+    let mut ffmpeg = ffmpeg(&opts.tools);
+    // `out` may have been reserved by creating it empty
+    ffmpeg.arg("-hide_banner").arg("-y").arg("-v").arg("error");
+    if let (Some(audio), Some(video)) = (audio, video)
+        && opts.sync
+    {
+        // ffmpeg shifts each input to start at zero, which loses any offset between separately
+        // downloaded audio and video that's meant to be preserved. Restore it by delaying (or
+        // advancing) the audio by its start time relative to the video.
+        match (audio.start_time, video.start_time) {
+            (Some(audio_start), Some(video_start)) => {
+                let offset = audio_start - video_start;
+                if offset.unsigned_abs() >= Duration::from_millis(1) {
+                    info!(
+                        "Offsetting audio in {} by {:.3}s to sync it with the video",
+                        out.display(),
+                        offset.as_secs_f64()
+                    );
+                    ffmpeg
+                        .arg("-itsoffset")
+                        .arg(format!("{:.6}", offset.as_secs_f64()));
+                }
+            }
+            _ => warnings.warn(
+                "unknown-start-time",
+                Some(&group[0].path),
+                format!(
+                    "Can't sync audio and video in {}, start time unknown",
+                    out.display()
+                ),
+            ),
+        }
+    }
+    let concat = match &parts {
+        Some(parts) => {
+            let video = &parts[0].path;
+            warnings.warn(
+                "concatenated",
+                Some(video),
+                format!(
+                    "Concatenating {} parts of {} into {}",
+                    parts.len(),
+                    video.display(),
+                    out.display()
+                ),
+            );
+            let script = temp_dir().join(format!("instagrouper-concat-{}.txt", Uuid::now_v7()));
+            std::fs::write(
+                &script,
+                concat_script(parts.iter().map(|mi| mi.path.as_path())),
+            )
+            .with_context(|| format!("Error writing concat script to {}", script.display()))?;
+            Some(script)
+        }
+        None => None,
+    };
+    let inputs: Vec<_> = [audio, video, subtitle].into_iter().flatten().collect();
+    for input in &inputs {
+        match &concat {
+            Some(script) if video.is_some_and(|video| std::ptr::eq(*input, video)) => {
+                // Parts may be URLs, which the concat demuxer must be allowed to open
+                ffmpeg
+                    .arg("-f")
+                    .arg("concat")
+                    .arg("-safe")
+                    .arg("0")
+                    .arg("-protocol_whitelist")
+                    .arg("file,http,https,tcp,tls,crypto")
+                    .arg("-i")
+                    .arg(script);
+            }
+            _ => {
+                ffmpeg.arg("-i").arg(&input.path);
+            }
+        }
+    }
+    if inputs.len() > 1 {
+        // Take only the stream each input was selected for, so that e.g. the audio track of a
+        // muxed video input doesn't end up in the output alongside the separate audio.
+        for (n, input) in inputs.iter().enumerate() {
+            ffmpeg
+                .arg("-map")
+                .arg(format!("{n}:{}", input.stream_index));
+        }
+    }
+    ffmpeg.arg("-c").arg("copy");
+    if subtitle.is_some()
+        && let Some(encoder) = container.subtitle_encoder()
+    {
+        // MP4 only carries subtitles as `mov_text` (and WebM as WebVTT), so convert them from
+        // e.g. SubRip
+        ffmpeg.arg("-c:s").arg(encoder);
+    }
+    // Date the output after its video (or else its audio), rather than after the first input
+    // whose metadata ffmpeg copies over otherwise
+    let dated = video.or(audio).unwrap();
+    ffmpeg
+        .arg("-metadata")
+        .arg(format!("creation_time={}", dated.timestamp));
+    if let Some(rotation) = video.and_then(|video| video.rotation)
+        && !reencode
+        && trim.is_none()
+        && reencode_video.is_none()
+    {
+        // Stream copies keep their display matrix, but older versions of ffmpeg only carry the
+        // rotation over as a (clockwise) tag. Re-encoded video is rotated upright instead.
+        ffmpeg
+            .arg("-metadata:s:v:0")
+            .arg(format!("rotate={}", (-rotation).rem_euclid(360)));
+    }
+    if fragmented && container == Container::Mp4 {
+        // A stream copy is written out unfragmented, but put the index up front too so the
+        // output is seekable as soon as it starts loading.
+        info!(
+            "Remuxing fragmented MP4 input into a regular MP4 at {}",
+            out.display()
+        );
+        ffmpeg.arg("-movflags").arg("+faststart");
+    }
+    let (audio_encoder, video_encoder) = (container.audio_encoder(), container.video_encoder());
+    for (mi, (encoder, name)) in [
+        (reencode_audio, audio_encoder),
+        (reencode_video, video_encoder),
+    ] {
+        let Some(mi) = mi else {
+            continue;
+        };
+        warnings.warn(
+            "reencoded",
+            Some(&mi.path),
+            format!(
+                "Re-encoding {} from {} to {name}",
+                mi.path.display(),
+                mi.codec
+            ),
+        );
+        let stream = if mi.is_audio() { "-c:a" } else { "-c:v" };
+        ffmpeg.arg(stream).arg(encoder);
+    }
+    if let Some((start, end)) = trim {
+        let video = &video.unwrap().path;
+        let trimmed_end = video_duration(group, opts).saturating_sub(end);
+        warnings.warn(
+            "trimmed-black",
+            Some(video),
+            format!(
+                "Trimming {start:?} of black frames off the start and {trimmed_end:?} off the \
+                 end of {}",
+                video.display()
+            ),
+        );
+        ffmpeg
+            .arg("-ss")
+            .arg(format!("{:.6}", start.as_secs_f64()))
+            .arg("-t")
+            .arg(format!("{:.6}", end.saturating_sub(start).as_secs_f64()));
+        if !reencode {
+            ffmpeg.arg("-c:v").arg(video_encoder.0);
+        }
+    }
+    if reencode {
+        let pix_fmt = opts.pix_fmt.as_ref().unwrap();
+        let video_mi = video.unwrap();
+        let video = &video_mi.path;
+        warnings.warn(
+            "reencoded",
+            Some(video),
+            format!("Re-encoding {} to {pix_fmt}", video.display()),
+        );
+        ffmpeg
+            .arg("-c:v")
+            .arg(video_encoder.0)
+            .arg("-pix_fmt")
+            .arg(pix_fmt);
+        if let Some(resolution) = video_mi.resolution.filter(|res| !res.is_even()) {
+            // Most pixel formats we'd be asked for are chroma subsampled, which libx264 can't
+            // encode at odd dimensions.
+            let even = resolution.scaled_to_fit(resolution);
+            ffmpeg
+                .arg("-vf")
+                .arg(format!("scale={}:{}", even.width, even.height));
+        }
+    }
+    ffmpeg.arg("-f").arg(container.muxer()).arg(out);
+    // The longest of the streams merged, counting every part of the video
+    let video_duration = parts
+        .as_ref()
+        .map(|parts| parts.iter().map(|part| part.duration).sum())
+        .or(video.map(|video| video.duration));
+    let longest = audio
+        .map(|audio| audio.duration)
+        .max(video_duration)
+        .unwrap();
+    let total = match trim {
+        Some((start, end)) => end.saturating_sub(start),
+        None => longest,
+    };
+    let ffmpeg = run_with_progress(&mut ffmpeg, |ffmpeg| {
+        let merging = MergeProgress {
+            out,
+            done: ffmpeg.out_time.unwrap_or_default().min(total),
+            total,
+            speed: ffmpeg.speed,
+        };
+        debug!(
+            "Merging {}: {:.0}% at {}x",
+            out.display(),
+            merging.fraction() * 100.0,
+            merging
+                .speed
+                .map_or("?".to_owned(), |speed| speed.to_string())
+        );
+        if let Some(progress) = progress {
+            progress(Event::Merging(merging));
+        }
+    });
+    if let Some(script) = &concat
+        && let Err(err) = std::fs::remove_file(script)
+    {
+        warn!(
+            "Error cleaning up concat script at {}: {err}",
+            script.display()
+        );
+    }
+    let ffmpeg = ffmpeg.context("Error running ffmpeg!")?;
+
+    if !ffmpeg.status.success() {
+        log_stderr(&ffmpeg.stderr);
+        bail!("Error merging media");
+    }
+
+    // Trimmed outputs are meant to be shorter
+    if let Some(max_delta) = opts.verify_duration
+        && trim.is_none()
+        && let Err(err) = verify_duration(out, longest, max_delta, &opts.tools)
+    {
+        match opts.strict {
+            true => return Err(err),
+            false => warnings.warn("duration-mismatch", Some(out), format!("{err:#}")),
+        }
+    }
+
+    let reencoded =
+        reencode || trim.is_some() || reencode_audio.is_some() || reencode_video.is_some();
+    let fname = out.file_name().unwrap();
+    match (audio, video) {
+        (Some(_), Some(_)) if subtitle.is_some() => info!(
+            "Merged audio, video, and subtitles into {}",
+            fname.display()
+        ),
+        (Some(_), Some(_)) => info!("Merged audio and video into {}", fname.display()),
+        _ if reencoded => info!("Re-encoded input into {}", fname.display()),
+        _ => info!("Remuxed input into {}", fname.display()),
+    }
+
+    Ok(Merged {
+        kind: merge_kind(group),
+        copied: false,
+        reencoded,
+        resolution: video.and_then(|video| video.resolution),
+    })
+}
+
+/// Checks that merged output `out` is within `max_delta` of the `expected` duration, as
+/// ffmpeg may produce a truncated output (e.g. when its audio is shorter than its video)
+/// without failing.
+pub fn verify_duration(
+    out: &Path,
+    expected: Duration,
+    max_delta: Duration,
+    tools: &Tools,
+) -> Result<()> {
+    let actual = identify(out, tools)
+        .with_context(|| format!("Error identifying {}", out.display()))?
+        .duration;
+    if actual.abs_diff(expected) > max_delta {
+        bail!(
+            "{} is {actual:?} long rather than the {expected:?} expected, it may be truncated",
+            out.display()
+        );
+    }
+    Ok(())
+}
+
+/// The duration of the video [`merge()`] selects from `group`.
+fn video_duration(group: &[MediaInfo], opts: &MergeOptions) -> Duration {
+    select_streams(group, opts)
+        .1
+        .map_or(Duration::ZERO, |video| video.duration)
+}
+
+/// Detects the segments of black frames in `video`, as `(start, end)` pairs.
+pub fn black_segments(video: &MediaInfo, tools: &Tools) -> Result<Vec<(Duration, Duration)>> {
+    let ffmpeg = ffmpeg(tools)
+        .arg("-hide_banner")
+        .arg("-nostats")
+        .arg("-i")
+        .arg(&video.path)
+        .arg("-map")
+        .arg(format!("0:{}", video.stream_index))
+        .arg("-vf")
+        .arg("blackdetect=d=0.1:pix_th=0.10")
+        .arg("-f")
+        .arg("null")
+        .arg("-")
+        .output()
+        .context("Error running ffmpeg!")?;
+
+    if !ffmpeg.status.success() {
+        log_stderr(&ffmpeg.stderr);
+        bail!("Error decoding {}", video.path.display());
+    }
+
+    Ok(parse_blackdetect(&String::from_utf8_lossy(&ffmpeg.stderr)))
+}
+
+/// Parses the segments `blackdetect` logs, e.g.
+/// `[blackdetect @ 0x5581] black_start:0 black_end:1.48 black_duration:1.48`.
+fn parse_blackdetect(log: &str) -> Vec<(Duration, Duration)> {
+    let value = |line: &str, key: &str| {
+        let (_, rest) = line.split_once(key)?;
+        let value = rest.split_whitespace().next()?;
+        Duration::try_from_secs_f64(value.parse().ok()?).ok()
+    };
+
+    log.lines()
+        .filter(|line| line.contains("[blackdetect @"))
+        .filter_map(|line| Some((value(line, "black_start:")?, value(line, "black_end:")?)))
+        .collect()
+}
+
+/// The `(start, end)` of the video of length `duration` left after trimming off the black
+/// `segments` at its very start and end (if any).
+pub fn black_trim(segments: &[(Duration, Duration)], duration: Duration) -> (Duration, Duration) {
+    // How far from the very start or end a segment can be and still be trimmed
+    let edge = Duration::from_millis(100);
+
+    let start = segments
+        .iter()
+        .find(|(start, _)| *start <= edge)
+        .map_or(Duration::ZERO, |&(_, end)| end);
+    let end = segments
+        .iter()
+        .rfind(|(_, end)| *end + edge >= duration)
+        .map_or(duration, |&(start, _)| start);
+
+    if end <= start {
+        // All black, don't trim it into nothing
+        return (Duration::ZERO, duration);
+    }
+    (start, end)
+}
+
+#[test]
+fn black_frame_trimming() {
+    let log = "\
+        [blackdetect @ 0x5581c0] black_start:0 black_end:1.48 black_duration:1.48\n\
+        frame=  300 fps=0.0 q=-0.0 size=N/A time=00:00:10.00\n\
+        [blackdetect @ 0x5581c0] black_start:4 black_end:4.5 black_duration:0.5\n\
+        [blackdetect @ 0x5581c0] black_start:9.2 black_end:10 black_duration:0.8\n";
+    let secs = Duration::from_secs_f64;
+    let segments = parse_blackdetect(log);
+    assert_eq!(
+        segments,
+        [
+            (secs(0.0), secs(1.48)),
+            (secs(4.0), secs(4.5)),
+            (secs(9.2), secs(10.0))
+        ]
+    );
+
+    // Black frames in the middle are kept
+    assert_eq!(black_trim(&segments, secs(10.0)), (secs(1.48), secs(9.2)));
+    assert_eq!(
+        black_trim(&segments[1..2], secs(10.0)),
+        (secs(0.0), secs(10.0))
+    );
+    assert_eq!(
+        black_trim(&[(secs(0.0), secs(10.0))], secs(10.0)),
+        (secs(0.0), secs(10.0))
+    );
+}
+
+/// Saves remote input `url` to `out` as-is, the equivalent of [`std::fs::copy()`] for URLs.
+fn download(url: &Path, out: &Path, tools: &Tools) -> Result<()> {
+    let ffmpeg = ffmpeg(tools)
+        .arg("-hide_banner")
+        .arg("-v")
+        .arg("error")
+        .arg("-i")
+        .arg(url)
+        .arg("-c")
+        .arg("copy")
+        .arg(out)
+        .output()
+        .context("Error running ffmpeg!")?;
+
+    if !ffmpeg.status.success() {
+        log_stderr(&ffmpeg.stderr);
+        bail!("Error downloading {}", url.display());
+    }
+
+    Ok(())
+}
+
+/// Where in the media [`thumbnail()`] takes the thumbnail frame from.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ThumbnailPosition {
+    /// The very first frame of media shorter than a second, 2 seconds into media shorter than
+    /// 6 seconds, and 5 seconds into anything longer.
+    #[default]
+    Auto,
+    /// At this timestamp
+    At(Duration),
+    /// At this percentage (0 to 100) of the duration of the media
+    Percent(f64),
+}
+
+impl FromStr for ThumbnailPosition {
+    type Err = anyhow::Error;
+
+    /// Parses a percentage (e.g. `30%`) or a timestamp (e.g. `00:07` or `7.5`)
+    fn from_str(s: &str) -> Result<Self> {
+        match s.strip_suffix('%') {
+            Some(percent) => {
+                let percent = percent
+                    .parse::<f64>()
+                    .with_context(|| format!("Invalid percentage {s}"))?;
+                if !(0.0..=100.0).contains(&percent) {
+                    bail!("Invalid percentage {s}, must be between 0% and 100%");
+                }
+                Ok(ThumbnailPosition::Percent(percent))
+            }
+            None => Ok(ThumbnailPosition::At(s.parse::<PrettyDuration>()?.0)),
+        }
+    }
+}
+
+/// How far before the end of the media [`ThumbnailPosition`]s are clamped to, so there's still
+/// a frame to take.
+const THUMBNAIL_END_MARGIN: Duration = Duration::from_millis(100);
+
+/// The position of the frame to take the thumbnail of media of length `duration` from.
+fn thumbnail_position(duration: Duration, position: ThumbnailPosition) -> Duration {
+    let start = match position {
+        ThumbnailPosition::Auto => match duration.as_secs() {
+            0 => Duration::ZERO,
+            1..6 => Duration::from_secs(2),
+            _ => Duration::from_secs(5),
+        },
+        ThumbnailPosition::At(start) => start,
+        ThumbnailPosition::Percent(percent) => duration.mul_f64(percent / 100.0),
+    };
+    start.min(duration.saturating_sub(THUMBNAIL_END_MARGIN))
+}
+
+#[test]
+fn thumbnail_positions() {
+    let secs = Duration::from_secs;
+    let position = |duration, position: &str| {
+        thumbnail_position(duration, position.parse().unwrap()).as_millis()
+    };
+
+    assert_eq!(thumbnail_position(secs(0), Default::default()), secs(0));
+    assert_eq!(thumbnail_position(secs(3), Default::default()), secs(2));
+    assert_eq!(thumbnail_position(secs(60), Default::default()), secs(5));
+    assert_eq!(position(secs(60), "30%"), 18_000);
+    assert_eq!(position(secs(60), "00:07"), 7_000);
+    assert_eq!(position(secs(60), "7.5"), 7_500);
+    // Clamped to (just before) the end
+    assert_eq!(position(secs(2), "100%"), 1_900);
+    assert_eq!(position(secs(2), "00:07"), 1_900);
+    assert_eq!(
+        thumbnail_position(secs(1), Default::default()),
+        secs(1) - THUMBNAIL_END_MARGIN
+    );
+
+    for invalid in ["", "-5%", "101%", "%", "soon"] {
+        assert!(invalid.parse::<ThumbnailPosition>().is_err(), "{invalid:?}");
+    }
+}
+
+/// Computes a perceptual hash (a 64-bit dHash) of the frame of video or image `src` its
+/// thumbnail is taken from. Visually similar frames have hashes differing in few bits, see
+/// [`hash_distance()`].
+pub fn perceptual_hash(
+    src: &Path,
+    duration: Duration,
+    position: ThumbnailPosition,
+    tools: &Tools,
+) -> Result<u64> {
+    let ffmpeg = ffmpeg(tools)
+        .arg("-hide_banner")
+        .arg("-v")
+        .arg("error")
+        .arg("-ss")
+        .arg(format!(
+            "{:.3}",
+            thumbnail_position(duration, position).as_secs_f64()
+        ))
+        .arg("-i")
+        .arg(src)
+        .arg("-frames:v")
+        .arg("1")
+        .arg("-vf")
+        .arg("scale=9:8:flags=area,format=gray")
+        .arg("-f")
+        .arg("rawvideo")
+        .arg("-")
+        .output()
+        .context("Error running ffmpeg!")?;
+
+    if !ffmpeg.status.success() {
+        log_stderr(&ffmpeg.stderr);
+        bail!("Error extracting frame to hash");
+    }
+
+    dhash(&ffmpeg.stdout)
+}
+
+/// Computes the dHash of a 9x8 8-bit grayscale image: one bit per pair of horizontally
+/// adjacent pixels, set if the brightness increases from left to right.
+fn dhash(pixels: &[u8]) -> Result<u64> {
+    if pixels.len() != 9 * 8 {
+        bail!("Expected a 9x8 frame, got {} bytes", pixels.len());
+    }
+
+    let mut hash = 0u64;
+    for row in pixels.chunks_exact(9) {
+        for pair in row.windows(2) {
+            hash = (hash << 1) | (pair[0] < pair[1]) as u64;
+        }
+    }
+
+    Ok(hash)
+}
+
+/// The number of bits two [`perceptual_hash()`]es differ by.
+pub fn hash_distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+#[test]
+fn dhash_gradients() {
+    let rising: Vec<u8> = (0..8).flat_map(|_| 0..9u8).map(|x| x * 20).collect();
+    let falling: Vec<u8> = rising.iter().map(|x| 255 - x).collect();
+    let noisy: Vec<u8> = rising.iter().map(|x| x.saturating_add(3)).collect();
+
+    assert_eq!(dhash(&rising).unwrap(), u64::MAX);
+    assert_eq!(dhash(&falling).unwrap(), 0);
+    assert_eq!(
+        hash_distance(dhash(&rising).unwrap(), dhash(&noisy).unwrap()),
+        0
+    );
+    assert!(dhash(&rising[1..]).is_err());
+}
+
+/// How [`thumbnail()`] seeks to the frame it takes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ThumbnailSeek {
+    /// Seek quickly to the very first frame, and accurately to any later one.
+    #[default]
+    Auto,
+    /// Seek the input (`-ss` before `-i`), jumping straight to the nearest preceding keyframe.
+    /// Fast, but the frame taken may be some way off from the one requested.
+    Fast,
+    /// Seek the output (`-ss` after `-i`), decoding and discarding every frame up to the one
+    /// requested. Frame-accurate, but slower the further into the media the frame is.
+    Accurate,
+}
+
+/// The default [`ThumbnailOptions::max_edge`]
+pub const DEFAULT_THUMBNAIL_MAX_EDGE: u16 = 1280;
+
+/// The aspect ratio `width:height` of a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AspectRatio {
+    pub width: u16,
+    pub height: u16,
+}
+
+/// How a frame is fit to an [`AspectRatio`] other than its own.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AspectFit {
+    /// Letterbox or pillarbox the frame, padding it to the aspect ratio.
+    #[default]
+    Pad,
+    /// Crop the frame to the aspect ratio, keeping its center.
+    Crop,
+}
+
+impl FromStr for AspectRatio {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let Some((width, height)) = s.split_once(':') else {
+            bail!("Invalid aspect ratio {s}, expected e.g. 1:1 or 16:9");
+        };
+        let aspect = AspectRatio {
+            width: width.parse().context("Invalid aspect ratio width")?,
+            height: height.parse().context("Invalid aspect ratio height")?,
+        };
+        if aspect.width == 0 || aspect.height == 0 {
+            bail!("Invalid aspect ratio {s}, must not be zero");
+        }
+        Ok(aspect)
+    }
+}
+
+impl AspectRatio {
+    /// The (even) dimensions `resolution` is padded or cropped to with `fit`.
+    pub fn frame(&self, resolution: Resolution, fit: AspectFit) -> Resolution {
+        let (w, h) = (resolution.width as u32, resolution.height as u32);
+        let (aw, ah) = (self.width as u32, self.height as u32);
+        let even = |len: u32| (len.min(u16::MAX as u32) as u16 & !1).max(2);
+        let wider = w * ah > h * aw;
+        let (width, height) = match (fit, wider) {
+            (AspectFit::Pad, true) | (AspectFit::Crop, false) => (w, w * ah / aw),
+            (AspectFit::Pad, false) | (AspectFit::Crop, true) => (h * aw / ah, h),
+        };
+        Resolution {
+            width: even(width),
+            height: even(height),
+        }
+    }
+
+    /// The ffmpeg filter padding (with `color`) or cropping a frame to `self` with `fit`. The
+    /// frame is measured as it's filtered, so that it's fit correctly after being rotated.
+    fn filter(&self, fit: AspectFit, color: &str) -> String {
+        let AspectRatio { width, height } = self;
+        match fit {
+            AspectFit::Pad => format!(
+                "pad=w='trunc(max(iw,ih*{width}/{height})/2)*2'\
+                 :h='trunc(max(ih,iw*{height}/{width})/2)*2'\
+                 :x=(ow-iw)/2:y=(oh-ih)/2:color={color}"
+            ),
+            AspectFit::Crop => format!(
+                "crop=w='trunc(min(iw,ih*{width}/{height})/2)*2'\
+                 :h='trunc(min(ih,iw*{height}/{width})/2)*2'"
+            ),
+        }
+    }
+}
+
+/// The ffmpeg filter scaling a frame to exactly `size`, preserving its aspect ratio by padding
+/// (with `color`) or cropping it to `size` with `fit`.
+fn size_filter(size: Resolution, fit: AspectFit, color: &str) -> String {
+    let Resolution { width, height } = size;
+    match fit {
+        AspectFit::Pad => format!(
+            "scale={width}:{height}:force_original_aspect_ratio=decrease,\
+             pad={width}:{height}:(ow-iw)/2:(oh-ih)/2:color={color}"
+        ),
+        AspectFit::Crop => format!(
+            "scale={width}:{height}:force_original_aspect_ratio=increase,\
+             crop={width}:{height}"
+        ),
+    }
+}
+
+#[test]
+fn aspect_ratio_frame() {
+    let res = |width, height| Resolution { width, height };
+    let square: AspectRatio = "1:1".parse().unwrap();
+    let portrait: AspectRatio = "9:16".parse().unwrap();
+
+    assert_eq!(
+        square.frame(res(1920, 1080), AspectFit::Pad),
+        res(1920, 1920)
+    );
+    assert_eq!(
+        square.frame(res(1080, 1920), AspectFit::Pad),
+        res(1920, 1920)
+    );
+    assert_eq!(
+        square.frame(res(1920, 1080), AspectFit::Crop),
+        res(1080, 1080)
+    );
+    assert_eq!(
+        square.frame(res(1080, 1920), AspectFit::Crop),
+        res(1080, 1080)
+    );
+    assert_eq!(
+        portrait.frame(res(1280, 720), AspectFit::Pad),
+        res(1280, 2274)
+    );
+    assert_eq!(
+        portrait.frame(res(1280, 720), AspectFit::Crop),
+        res(404, 720)
+    );
+    assert_eq!(
+        portrait.frame(res(720, 1280), AspectFit::Pad),
+        res(720, 1280)
+    );
+    assert!("0:1".parse::<AspectRatio>().is_err());
+    assert!("16x9".parse::<AspectRatio>().is_err());
+}
+
+/// Options controlling how [`thumbnail()`] generates thumbnails.
+#[derive(Debug, Clone)]
+pub struct ThumbnailOptions {
+    pub position: ThumbnailPosition,
+    pub seek: ThumbnailSeek,
+    /// Downscale thumbnails with a longer edge than this, preserving their aspect ratio.
+    /// `None` keeps the native resolution of the video.
+    pub max_edge: Option<u16>,
+    /// Scale thumbnails to exactly this size, padding or cropping them to it (see `fit`)
+    /// rather than stretching them. Overrides `max_edge` and `aspect`.
+    pub size: Option<Resolution>,
+    /// A filter chain (e.g. `select='gt(scene,0.4)'` or `thumbnail`) picking the frame the
+    /// thumbnail is taken from, applied to the video before it's scaled and overlaid. See
+    /// [`validate_thumbnail_filter()`].
+    pub filter: Option<String>,
+    /// The aspect ratio to pad or crop thumbnails to, if not that of the video
+    pub aspect: Option<AspectRatio>,
+    pub fit: AspectFit,
+    /// The ffmpeg color (e.g. `black` or `#ffffff`) to pad thumbnails with
+    pub pad_color: String,
+    /// Draw the play overlay over thumbnails of video, or else take just the frame
+    pub play_overlay: bool,
+    pub format: ThumbnailFormat,
+    /// The JPEG quality scale (`-q:v`) to encode thumbnails with, from 2 (best) to 31 (worst).
+    /// `None` leaves it to ffmpeg.
+    pub quality: Option<u8>,
+    /// Overwrite an existing thumbnail, see [`refuse_overwrite()`]
+    pub overwrite: bool,
+    /// The ffmpeg hardware decoder (`-hwaccel`, e.g. `auto`, `vaapi`, or `videotoolbox`) to
+    /// decode video with, falling back to software decoding if it fails. `None` decodes in
+    /// software.
+    pub hwaccel: Option<String>,
+    /// The ffmpeg and ffprobe binaries thumbnails are generated with
+    pub tools: Tools,
+}
+
+/// The image format [`thumbnail()`] writes thumbnails in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ThumbnailFormat {
+    #[default]
+    Jpeg,
+    Png,
+    Webp,
+    Gif,
+}
+
+impl ThumbnailFormat {
+    pub const ALL: [ThumbnailFormat; 4] = [
+        ThumbnailFormat::Jpeg,
+        ThumbnailFormat::Png,
+        ThumbnailFormat::Webp,
+        ThumbnailFormat::Gif,
+    ];
+
+    /// The extension of thumbnails in this format, e.g. `jpg`
+    pub fn extension(self) -> &'static str {
+        match self {
+            ThumbnailFormat::Jpeg => "jpg",
+            ThumbnailFormat::Png => "png",
+            ThumbnailFormat::Webp => "webp",
+            ThumbnailFormat::Gif => "gif",
+        }
+    }
+
+    /// The ffmpeg image encoder of this format
+    fn encoder(self) -> &'static str {
+        match self {
+            ThumbnailFormat::Jpeg => "mjpeg",
+            ThumbnailFormat::Png => "png",
+            ThumbnailFormat::Webp => "libwebp",
+            ThumbnailFormat::Gif => "gif",
+        }
+    }
+}
+
+impl FromStr for ThumbnailFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "jpeg" | "jpg" => Ok(ThumbnailFormat::Jpeg),
+            "png" => Ok(ThumbnailFormat::Png),
+            "webp" => Ok(ThumbnailFormat::Webp),
+            "gif" => Ok(ThumbnailFormat::Gif),
+            _ => bail!("Invalid thumbnail format {s}, expected jpeg, png, webp, or gif"),
+        }
+    }
+}
+
+impl Default for ThumbnailOptions {
+    fn default() -> Self {
+        ThumbnailOptions {
+            position: Default::default(),
+            seek: Default::default(),
+            max_edge: Some(DEFAULT_THUMBNAIL_MAX_EDGE),
+            size: None,
+            filter: None,
+            aspect: None,
+            fit: Default::default(),
+            pad_color: "black".to_owned(),
+            play_overlay: true,
+            format: Default::default(),
+            quality: None,
+            overwrite: false,
+            hwaccel: None,
+            tools: Tools::default(),
+        }
+    }
+}
+
+/// Checks that `filter` is a single, unlabeled filter chain, which the rest of the thumbnail
+/// filter graph can be chained onto.
+pub fn validate_thumbnail_filter(filter: &str) -> Result<()> {
+    if filter.trim().is_empty() {
+        bail!("Empty thumbnail filter");
+    }
+    if filter.contains(';') {
+        bail!("Thumbnail filter must be a single filter chain, without `;`");
+    }
+    if filter.contains('[') {
+        bail!("Thumbnail filter must not use `[...]` link labels");
+    }
+    Ok(())
+}
+
+#[test]
+fn thumbnail_filter_validation() {
+    assert!(validate_thumbnail_filter("select='gt(scene,0.4)'").is_ok());
+    assert!(validate_thumbnail_filter("thumbnail=50,eq=brightness=0.1").is_ok());
+    assert!(validate_thumbnail_filter(" ").is_err());
+    assert!(validate_thumbnail_filter("null[a]; [a]null").is_err());
+    assert!(validate_thumbnail_filter("[0:v]select='eq(n,10)'").is_err());
+}
+
+/// Builds the `-filter_complex` graph overlaying the play icon (input 1) on the frame of the
+/// video (input 0) a thumbnail is taken from, given the resolution of the video (if known).
+fn thumbnail_filter(resolution: Option<Resolution>, opts: &ThumbnailOptions) -> String {
+    #[allow(unused)]
+    enum FfVersion {
+        /// For compatibility with FFmpeg 6.0 (and older versions we don't officially support)
+        ///
+        /// Runs on even the latest versions of FFmpeg, but generates a deprecation warning on
+        /// FFmpeg 7 or later.
+        Compat,
+        /// Only supports FFmpeg 7+
+        Ffmpeg7,
+    }
+
+    fn ffmpeg_vf(version: FfVersion) -> &'static str {
+        match version {
+            FfVersion::Compat => {
+                // [1:v][0:v] takes (1) logo and (2) video.
+                // 'main_w' and 'main_h' refer to the second input ([0:v] / the video).
+                // It outputs two streams: [logo] (the scaled icon) and [video] (the original
+                // video). We then overlay [logo] on [video].
                 //
                 // Unlike the FFmpeg 7 version, this does not correctly scale preserving aspect
                 // ratio if `h=-1` is used, so we're forced to calculate both and manually
@@ -283,114 +3423,1065 @@ pub fn thumbnail(src: &Path, out: &Path) -> Result<()> {
                     "[video][logo]overlay=(W-w)/2:(H-h)/2:shortest=1"
                 )
             }
-            FfVersion::Ffmpeg7 => {
-                // [0:v]split[main][ref] -> Create two copies of the video.
-                // [1:v][ref]scale=...[logo] -> Scale the logo (input 1) using the 'ref'
-                // copy for dimensions. 'rw' and 'rh' are the reference width/height
-                // of the 2nd input ([ref]).
-                // [main][logo]overlay=... -> Overlay the scaled logo onto the 'main' video copy.
-                "[0:v]split[main][ref]; \
-                  [1:v][ref]scale=w='min(rw,rh)*0.4':h=-1[logo]; \
-                  [main][logo]overlay=(W-w)/2:(H-h)/2:shortest=1"
+            FfVersion::Ffmpeg7 => {
+                // [0:v]split[main][ref] -> Create two copies of the video.
+                // [1:v][ref]scale=...[logo] -> Scale the logo (input 1) using the 'ref'
+                // copy for dimensions. 'rw' and 'rh' are the reference width/height
+                // of the 2nd input ([ref]).
+                // [main][logo]overlay=... -> Overlay the scaled logo onto the 'main' video copy.
+                "[0:v]split[main][ref]; \
+                  [1:v][ref]scale=w='min(rw,rh)*0.4':h=-1[logo]; \
+                  [main][logo]overlay=(W-w)/2:(H-h)/2:shortest=1"
+            }
+        }
+    }
+
+    let filter = match (resolution, opts.size) {
+        // The frame was already scaled to size by the chain below
+        (Some(_), Some(size)) => thumbnail_overlay(size, ""),
+        (Some(mut resolution), None) => {
+            if let Some(aspect) = opts.aspect {
+                resolution = aspect.frame(resolution, opts.fit);
+            }
+
+            // Only ever downscale to the (square) bounds. Letting ffmpeg determine the exact
+            // size keeps this correct for rotated video, which ffmpeg rotates before filtering.
+            let mut scale = String::new();
+            if let Some(max) = opts.max_edge {
+                let bounds = Resolution {
+                    width: max,
+                    height: max,
+                };
+                if !resolution.fits_within(bounds) {
+                    resolution = resolution.scaled_to_fit(bounds);
+                    scale = format!(
+                        ",scale={max}:{max}:force_original_aspect_ratio=decrease\
+                         :force_divisible_by=2"
+                    );
+                }
+            }
+
+            thumbnail_overlay(resolution, &scale)
+        }
+        (None, _) => ffmpeg_vf(FfVersion::Compat).to_owned(),
+    };
+    // The user's filter picks the frame from the video, which is then fit to the aspect ratio
+    // (or size) before it's passed on to the above.
+    let aspect = match opts.size {
+        Some(size) => Some(size_filter(size, opts.fit, &opts.pad_color)),
+        None => opts
+            .aspect
+            .map(|aspect| aspect.filter(opts.fit, &opts.pad_color)),
+    };
+    let chain: Vec<&str> = [opts.filter.as_deref(), aspect.as_deref()]
+        .into_iter()
+        .flatten()
+        .collect();
+    if chain.is_empty() {
+        filter
+    } else {
+        format!(
+            "[0:v]{}[picked]; {}",
+            chain.join(","),
+            filter.replacen("[0:v]", "[picked]", 1)
+        )
+    }
+}
+
+/// Builds the `-vf` filter chain taking a thumbnail without the play overlay, i.e. just the
+/// picked frame, fit to the aspect ratio (or size) and downscaled like [`thumbnail_filter()`].
+fn frame_filter(opts: &ThumbnailOptions) -> String {
+    let fit = match opts.size {
+        Some(size) => Some(size_filter(size, opts.fit, &opts.pad_color)),
+        None => opts
+            .aspect
+            .map(|aspect| aspect.filter(opts.fit, &opts.pad_color)),
+    };
+    // Only ever downscale, measuring the frame as it's filtered rather than up front
+    let scale = opts.max_edge.filter(|_| opts.size.is_none()).map(|max| {
+        format!(
+            "scale='min(iw,{max})':'min(ih,{max})':force_original_aspect_ratio=decrease\
+             :force_divisible_by=2"
+        )
+    });
+    let chain: Vec<String> = [opts.filter.clone(), fit, scale]
+        .into_iter()
+        .flatten()
+        .collect();
+    if chain.is_empty() {
+        "null".to_owned()
+    } else {
+        chain.join(",")
+    }
+}
+
+/// The part of the thumbnail filter graph overlaying the play icon on a frame of `resolution`,
+/// once the frame is scaled with `scale` (a filter chain to append, if not empty).
+fn thumbnail_overlay(resolution: Resolution, scale: &str) -> String {
+    // With the dimensions of the video known up front, the (square) overlay can be scaled to
+    // 40% of its shorter side without relying on scale2ref or its FFmpeg 7 replacement.
+    let side = (resolution.width.min(resolution.height) as f64 * 0.4) as u16;
+    let logo = PLAY_OVERLAY_RESOLUTION.scaled_to_fit(Resolution {
+        width: side,
+        height: side,
+    });
+    format!(
+        "[0:v]null{scale}[video]; \
+         [1:v]scale={}:{}[logo]; \
+         [video][logo]overlay=(W-w)/2:(H-h)/2:shortest=1",
+        logo.width, logo.height
+    )
+}
+
+#[test]
+fn thumbnail_filter_orientations() {
+    let res = |width, height| Some(Resolution { width, height });
+    let opts = ThumbnailOptions::default();
+    let overlay = "[video][logo]overlay=(W-w)/2:(H-h)/2:shortest=1";
+
+    // The overlay is 40% of the shorter side, whichever that is
+    let landscape = thumbnail_filter(res(1280, 720), &opts);
+    assert_eq!(
+        landscape,
+        format!("[0:v]null[video]; [1:v]scale=288:288[logo]; {overlay}")
+    );
+    let portrait = thumbnail_filter(res(720, 1280), &opts);
+    assert_eq!(portrait, landscape);
+    let square = thumbnail_filter(res(1080, 1080), &opts);
+    assert_eq!(
+        square,
+        format!("[0:v]null[video]; [1:v]scale=432:432[logo]; {overlay}")
+    );
+
+    // Large frames (e.g. 4K HDR video or photos) are downscaled first, and the overlay sized
+    // for the downscaled frame
+    let scale = "scale=1280:1280:force_original_aspect_ratio=decrease:force_divisible_by=2";
+    assert_eq!(
+        thumbnail_filter(res(3840, 2160), &opts),
+        format!("[0:v]null,{scale}[video]; [1:v]scale=288:288[logo]; {overlay}")
+    );
+    assert_eq!(
+        thumbnail_filter(res(3024, 4032), &opts),
+        format!("[0:v]null,{scale}[video]; [1:v]scale=384:384[logo]; {overlay}")
+    );
+
+    // Without a known resolution, the overlay is scaled to the frame by ffmpeg
+    assert!(thumbnail_filter(None, &opts).starts_with("[1:v][0:v]scale2ref="));
+
+    // Custom filters are chained in before the rest
+    let opts = ThumbnailOptions {
+        filter: Some("thumbnail".to_owned()),
+        ..Default::default()
+    };
+    assert_eq!(
+        thumbnail_filter(res(1280, 720), &opts),
+        format!("[0:v]thumbnail[picked]; [picked]null[video]; [1:v]scale=288:288[logo]; {overlay}")
+    );
+    assert!(thumbnail_filter(None, &opts).contains("[1:v][picked]scale2ref="));
+
+    // Exact sizes are padded (or cropped) to, and the overlay sized for them
+    let opts = ThumbnailOptions {
+        size: res(320, 180),
+        aspect: Some("1:1".parse().unwrap()),
+        ..Default::default()
+    };
+    let sized = "scale=320:180:force_original_aspect_ratio=decrease,\
+                 pad=320:180:(ow-iw)/2:(oh-ih)/2:color=black";
+    assert_eq!(
+        thumbnail_filter(res(3024, 4032), &opts),
+        format!("[0:v]{sized}[picked]; [picked]null[video]; [1:v]scale=72:72[logo]; {overlay}")
+    );
+    assert!(thumbnail_filter(None, &opts).starts_with(&format!("[0:v]{sized}[picked]; ")));
+    let opts = ThumbnailOptions {
+        fit: AspectFit::Crop,
+        ..opts
+    };
+    assert!(thumbnail_filter(res(1280, 720), &opts).starts_with(
+        "[0:v]scale=320:180:force_original_aspect_ratio=increase,crop=320:180[picked]; "
+    ));
+
+    // Without the overlay, only the frame itself is filtered
+    let opts = ThumbnailOptions {
+        play_overlay: false,
+        ..Default::default()
+    };
+    assert_eq!(
+        frame_filter(&opts),
+        "scale='min(iw,1280)':'min(ih,1280)':force_original_aspect_ratio=decrease\
+         :force_divisible_by=2"
+    );
+    let opts = ThumbnailOptions {
+        max_edge: None,
+        ..opts
+    };
+    assert_eq!(frame_filter(&opts), "null");
+}
+
+pub fn thumbnail(src: &Path, out: &Path, opts: &ThumbnailOptions) -> Result<()> {
+    refuse_overwrite(out, opts.overwrite)?;
+    let mi = identify(src, &opts.tools).context("Error identifying file to screenshot")?;
+
+    if mi.is_audio() && mi.stream_count == 1 {
+        if opts.format == ThumbnailFormat::Png {
+            let mut file = File::create(out).with_context(|| {
+                format!("Error creating screenshot output file at {}", out.display())
+            })?;
+            file.write_all(audio_only_png())
+                .with_context(|| format!("Error writing screenshot to {}", out.display()))?;
+        } else {
+            convert_audio_only_png(out, opts)?;
+        }
+        return Ok(());
+    }
+
+    // Without the overlay, there's no overlay file to write either
+    let play_overlay = match opts.play_overlay {
+        true => Some(play_overlay_path()?),
+        false => None,
+    };
+
+    let start = thumbnail_position(mi.duration, opts.position);
+
+    let seek_accurately = match opts.seek {
+        ThumbnailSeek::Auto => !start.is_zero(),
+        ThumbnailSeek::Fast => false,
+        ThumbnailSeek::Accurate => true,
+    };
+    let start = format!("{:.3}", start.as_secs_f64());
+
+    let screenshot = |hwaccel: Option<&str>| {
+        let mut ffmpeg = ffmpeg(&opts.tools);
+        // `out` may have been reserved by creating it empty
+        ffmpeg.arg("-hide_banner").arg("-y");
+        // .arg("-v")
+        // .arg("error")
+        if let Some(hwaccel) = hwaccel {
+            // Without an -hwaccel_output_format, decoded frames are downloaded from GPU memory
+            // on their own, ready for the (software) filters that follow.
+            ffmpeg.arg("-hwaccel").arg(hwaccel);
+        }
+        if !seek_accurately {
+            ffmpeg.arg("-ss").arg(&start);
+        }
+        ffmpeg.arg("-i").arg(src);
+        match &play_overlay {
+            Some(play_overlay) => {
+                ffmpeg
+                    // Decode the overlay as the (still) WebP it is, rather than leaving it to
+                    // be probed, and loop it so it's always available at the same timestamp as
+                    // the video.
+                    .arg("-f")
+                    .arg("image2")
+                    .arg("-c:v")
+                    .arg("webp")
+                    .arg("-loop")
+                    .arg("1")
+                    .arg("-i")
+                    .arg(play_overlay)
+                    .arg("-filter_complex")
+                    .arg(thumbnail_filter(mi.resolution, opts));
+            }
+            None => {
+                ffmpeg.arg("-vf").arg(frame_filter(opts));
+            }
+        }
+        ffmpeg.arg("-frames:v").arg("1");
+        if seek_accurately {
+            ffmpeg.arg("-ss").arg(&start);
+        }
+        thumbnail_encoder(&mut ffmpeg, opts);
+        ffmpeg
+            .arg("-f")
+            .arg("image2")
+            .arg(out)
+            .output()
+            .context("Error running ffmpeg!")
+    };
+
+    let hwaccel = opts.hwaccel.as_deref().filter(|_| mi.is_video());
+    let mut ffmpeg = screenshot(hwaccel)?;
+    if let Some(hwaccel) = hwaccel
+        && !(ffmpeg.status.success() && out.metadata().is_ok_and(|md| md.len() > 0))
+    {
+        warn!(
+            "Error decoding {} with -hwaccel {hwaccel}, falling back to software decoding",
+            src.display()
+        );
+        debug!("{}", String::from_utf8_lossy(&ffmpeg.stderr).trim_end());
+        ffmpeg = screenshot(None)?;
+    }
+
+    if !ffmpeg.status.success() {
+        log_stderr(&ffmpeg.stderr);
+        bail!("Error taking screenshot");
+    }
+
+    if !out.metadata().is_ok_and(|md| md.len() > 0) {
+        log_stderr(&ffmpeg.stderr);
+        log_stderr(&ffmpeg.stdout);
+        bail!("Failed to generate screenshot with ffmpeg, refer to output.");
+    }
+
+    let fname = out.file_name().unwrap();
+    info!("Screenshot saved to {}", fname.display());
+
+    Ok(())
+}
+
+/// The default number of frames of an [`animated_preview()`]
+pub const DEFAULT_PREVIEW_FRAMES: u32 = 10;
+/// The default frame rate an [`animated_preview()`] plays back at
+pub const DEFAULT_PREVIEW_FPS: u32 = 2;
+/// The least time between the frames of an [`animated_preview()`], reducing the frame count of
+/// shorter clips.
+const MIN_PREVIEW_FRAME_SPACING: Duration = Duration::from_millis(250);
+
+/// The number of frames of an [`animated_preview()`] of media of length `duration`
+fn preview_frame_count(duration: Duration, frames: u32) -> u32 {
+    let fit = duration.as_millis() / MIN_PREVIEW_FRAME_SPACING.as_millis();
+    frames.min(fit.try_into().unwrap_or(u32::MAX)).max(1)
+}
+
+#[test]
+fn preview_frame_counts() {
+    let ms = Duration::from_millis;
+    assert_eq!(preview_frame_count(ms(60_000), 10), 10);
+    assert_eq!(preview_frame_count(ms(1_000), 10), 4);
+    assert_eq!(preview_frame_count(ms(100), 10), 1);
+    assert_eq!(preview_frame_count(Duration::ZERO, 10), 1);
+    assert_eq!(preview_frame_count(ms(60_000), 0), 1);
+}
+
+/// Generates an animated preview of `src` at `out`, of (up to) `frames` frames evenly spaced
+/// across its duration, played back at `fps`. It's an animated GIF if `out` is named `.gif`
+/// and an animated WebP otherwise, and sized like `opts` sizes thumbnails (without the play
+/// overlay). Inputs other than video get a still [`thumbnail()`] in the same format instead.
+pub fn animated_preview(
+    src: &Path,
+    out: &Path,
+    frames: u32,
+    fps: u32,
+    opts: &ThumbnailOptions,
+) -> Result<()> {
+    let format = match out.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) if ext.eq_ignore_ascii_case("gif") => ThumbnailFormat::Gif,
+        _ => ThumbnailFormat::Webp,
+    };
+    refuse_overwrite(out, opts.overwrite)?;
+    let mi = identify(src, &opts.tools).context("Error identifying file to preview")?;
+    if !mi.is_video() {
+        let opts = ThumbnailOptions {
+            format,
+            ..opts.clone()
+        };
+        return thumbnail(src, out, &opts);
+    }
+
+    // Sample the frames from the middle of each of `frames` equal spans of the video, then
+    // retime them to play back at `fps`
+    let count = preview_frame_count(mi.duration, frames);
+    let span = mi.duration.as_secs_f64() / count as f64;
+    let sizing = ThumbnailOptions {
+        filter: None,
+        ..opts.clone()
+    };
+    let mut filter = format!(
+        "fps=fps={:.6},{},setpts=N/({}*TB)",
+        1.0 / span.max(0.001),
+        frame_filter(&sizing),
+        fps.max(1)
+    );
+    if format == ThumbnailFormat::Gif {
+        // GIFs look far better with a palette generated for the frames at hand
+        filter.push_str(
+            ",split[frames][copy]; [frames]palettegen[palette]; [copy][palette]paletteuse",
+        );
+    }
+
+    let mut ffmpeg = ffmpeg(&opts.tools);
+    // `out` may have been reserved by creating it empty
+    ffmpeg
+        .arg("-hide_banner")
+        .arg("-y")
+        .arg("-v")
+        .arg("error")
+        .arg("-ss")
+        .arg(format!("{:.3}", span / 2.0))
+        .arg("-i")
+        .arg(src)
+        .arg("-vf")
+        .arg(filter)
+        .arg("-frames:v")
+        .arg(count.to_string())
+        .arg("-an")
+        .arg("-c:v")
+        .arg(format.encoder())
+        .arg("-loop")
+        .arg("0")
+        .arg("-f")
+        .arg(format.extension())
+        .arg(out);
+    let output = ffmpeg.output().context("Error running ffmpeg!")?;
+    if !output.status.success() || !out.metadata().is_ok_and(|md| md.len() > 0) {
+        bail!(
+            "Error generating animated preview: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    let fname = out.file_name().unwrap();
+    info!(
+        "Animated preview of {count} frames saved to {}",
+        fname.display()
+    );
+
+    Ok(())
+}
+
+/// A step of [`process()`] (or [`group_with_progress()`]) taken, reported as it happens.
+#[derive(Debug)]
+pub enum Event<'a> {
+    /// An input was identified
+    Identified(&'a Path),
+    /// The identified inputs were grouped into this many groups
+    Grouped(usize),
+    /// Some of a group was merged by ffmpeg (so far)
+    Merging(MergeProgress<'a>),
+    /// A group was merged into an attachment
+    Merged(&'a Processed),
+    /// A thumbnail was generated for an attachment
+    Thumbnailed(&'a Processed),
+    /// Processing an input (or the group it's in) failed, and it was skipped
+    Error(&'a Path, &'a anyhow::Error),
+}
+
+/// The progress of a [`merge_with_progress()`] under way.
+#[derive(Debug, Clone, Copy)]
+pub struct MergeProgress<'a> {
+    /// The attachment being written
+    pub out: &'a Path,
+    /// How much of the attachment has been written
+    pub done: Duration,
+    /// How long the attachment is expected to be, i.e. its longest stream
+    pub total: Duration,
+    /// How fast ffmpeg is merging, relative to real time (e.g. `2.5` for `2.5x`), if known
+    pub speed: Option<f64>,
+}
+
+impl MergeProgress<'_> {
+    /// How much of the attachment has been written, from 0 to 1.
+    pub fn fraction(&self) -> f64 {
+        match self.total.is_zero() {
+            true => 0.0,
+            false => self.done.as_secs_f64() / self.total.as_secs_f64(),
+        }
+    }
+}
+
+/// Receives the [`Event`]s of [`process()`].
+pub type Progress<'a> = &'a (dyn Fn(Event) + Sync);
+
+/// Options controlling how [`process()`] processes its inputs.
+#[derive(Debug, Clone, Default)]
+pub struct ProcessOptions {
+    pub group: GroupOptions,
+    pub merge: MergeOptions,
+    pub thumbnail: ThumbnailOptions,
+    /// Skip generating thumbnails
+    pub no_thumbnail: bool,
+}
+
+/// An attachment produced by [`process()`].
+#[derive(Debug, Clone)]
+pub struct Processed {
+    /// The inputs the attachment was created from
+    pub group: Vec<MediaInfo>,
+    pub path: PathBuf,
+    pub kind: MergeKind,
+    pub thumbnail: Option<PathBuf>,
+}
+
+/// Identifies and groups `paths`, then merges each group into an attachment (and thumbnail) in
+/// `out_dir`, named `{stub}_{NNN}.{ext}` after its first input.
+///
+/// Unlike [`group()`], inputs (and groups) that fail to be processed don't fail the whole
+/// batch, but are skipped and reported to `progress` (if given) along with the other steps.
+/// Images that don't belong to any audio or video are returned as-is.
+pub fn process<P: AsRef<Path> + Sync>(
+    paths: &[P],
+    out_dir: &Path,
+    opts: &ProcessOptions,
+    warnings: &Warnings,
+    progress: Option<Progress>,
+) -> Vec<Processed> {
+    let report = |event: Event<'_>| {
+        if let Some(progress) = progress {
+            progress(event);
+        }
+    };
+
+    let media_info = par_map(paths, opts.group.jobs, |_, path| {
+        let path = path.as_ref();
+        match identify_with(path, &opts.group, warnings) {
+            Ok(mi) => {
+                report(Event::Identified(path));
+                Some(mi)
+            }
+            Err(err) => {
+                report(Event::Error(path, &err));
+                None
+            }
+        }
+    });
+    let media_info = media_info.into_iter().flatten().collect();
+
+    let groups = group_media(media_info, &opts.group, warnings);
+    report(Event::Grouped(groups.len()));
+
+    let mut processed = Vec::with_capacity(groups.len());
+    for (n, group) in groups.into_iter().enumerate() {
+        if group.len() == 1 && group[0].is_image() {
+            let path = group[0].path.clone();
+            processed.push(Processed {
+                thumbnail: Some(path.clone()),
+                path,
+                kind: MergeKind::Image,
+                group,
+            });
+            continue;
+        }
+
+        let stub = name_prefix(&group[0].path).unwrap_or_else(|| Uuid::now_v7().to_string());
+        let ext = output_extension(&group, &opts.merge);
+        let path = out_dir.join(format!("{stub}_{n:0>3}.{ext}"));
+        let kind = match merge_with_progress(&group, &path, &opts.merge, warnings, progress) {
+            Ok(merged) => merged.kind,
+            Err(err) => {
+                report(Event::Error(&group[0].path, &err));
+                continue;
+            }
+        };
+        let mut attachment = Processed {
+            group,
+            path,
+            kind,
+            thumbnail: None,
+        };
+        report(Event::Merged(&attachment));
+
+        if !opts.no_thumbnail {
+            let ext = opts.thumbnail.format.extension();
+            let thumb_path = out_dir.join(format!("{stub}_{n:0>3}.{ext}"));
+            match thumbnail(&attachment.path, &thumb_path, &opts.thumbnail) {
+                Ok(()) => {
+                    attachment.thumbnail = Some(thumb_path);
+                    report(Event::Thumbnailed(&attachment));
+                }
+                Err(err) => report(Event::Error(&attachment.path, &err)),
+            }
+        }
+        processed.push(attachment);
+    }
+
+    processed
+}
+
+/// What [`identify()`] makes of an input. (De)serializable, e.g. to cache it and group it
+/// again later with [`group_identified()`] without re-running ffprobe.
+#[derive(Debug, Hash, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct MediaInfo {
+    pub stream_count: u8,
+    /// The index of the stream `codec`, `resolution`, etc. were taken from
+    pub stream_index: usize,
+    pub media: MediaType,
+    pub path: PathBuf,
+    pub codec: String,
+    pub size: u64,
+    #[serde(
+        serialize_with = "serialize_duration",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub duration: Duration,
+    pub timestamp: Timestamp,
+    pub resolution: Option<Resolution>,
+    pub bit_rate: Option<u32>,
+    /// The pixel format of video and image streams, e.g. `yuv420p`
+    pub pix_fmt: Option<String>,
+    /// The presentation time of the start of the stream, which may be negative (e.g. to skip
+    /// encoder priming samples)
+    pub start_time: Option<SignedDuration>,
+    /// Whether the input is a fragmented MP4, e.g. a DASH or HLS download, which isn't
+    /// (efficiently) seekable as-is
+    pub fragmented: bool,
+    /// The language of the stream, as tagged (usually an ISO 639-2 code, e.g. `eng`)
+    pub language: Option<String>,
+    /// The average frame rate of video streams
+    pub frame_rate: Option<FrameRate>,
+    /// The rotation of video streams in degrees counter-clockwise, as tagged (e.g. `90` or
+    /// `-90`), between -180 (exclusive) and 180. `resolution` already accounts for it, i.e. is
+    /// the displayed resolution.
+    pub rotation: Option<i16>,
+    /// The [`fingerprint()`] of video streams, only taken to group by [`MatchMode::Content`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<Vec<u64>>,
+    /// All the streams of the input, including the one the fields above describe
+    pub streams: Vec<StreamInfo>,
+}
+
+/// A `media` input at `path` lasting `secs`, for tests: 1280x720 if it's a video, and
+/// otherwise unremarkable.
+#[cfg(test)]
+fn media_info(path: &str, media: MediaType, secs: f64) -> MediaInfo {
+    MediaInfo {
+        stream_count: 1,
+        stream_index: 0,
+        resolution: (media == MediaType::Video).then_some(Resolution {
+            width: 1280,
+            height: 720,
+        }),
+        media,
+        path: PathBuf::from(path),
+        codec: String::new(),
+        size: 0,
+        duration: Duration::from_secs_f64(secs),
+        timestamp: Timestamp::UNIX_EPOCH,
+        bit_rate: None,
+        pix_fmt: None,
+        start_time: None,
+        fragmented: false,
+        language: None,
+        frame_rate: None,
+        rotation: None,
+        fingerprint: None,
+        streams: Vec::new(),
+    }
+}
+
+/// One of the [`MediaInfo::streams`] of an input.
+#[derive(Debug, Hash, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct StreamInfo {
+    pub index: usize,
+    /// The type of the stream as reported by ffprobe, e.g. `video`, `audio`, or `subtitle`
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub codec: String,
+    pub resolution: Option<Resolution>,
+    pub bit_rate: Option<u32>,
+    pub language: Option<String>,
+}
+
+impl MediaInfo {
+    pub fn is_audio(&self) -> bool {
+        self.media == MediaType::Audio
+    }
+
+    pub fn is_video(&self) -> bool {
+        self.media == MediaType::Video
+    }
+
+    pub fn is_image(&self) -> bool {
+        self.media == MediaType::Image
+    }
+
+    pub fn is_subtitle(&self) -> bool {
+        self.media == MediaType::Subtitle
+    }
+}
+
+/// Serializes a [`Duration`] as a string of seconds, the way ffprobe reports them and
+/// [`deserialize_duration()`] reads them back.
+pub fn serialize_duration<S: Serializer>(
+    duration: &Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(&format_args!("{:.6}", duration.as_secs_f64()))
+}
+
+pub fn deserialize_duration<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: From<Duration>,
+{
+    let secs = String::deserialize(deserializer)?
+        .parse::<f64>()
+        .context("Error deserializing Duration from string")
+        .map_err(serde::de::Error::custom)?;
+
+    let duration = Duration::try_from_secs_f64(secs)
+        .context("Error deserializing Duration from string")
+        .map_err(serde::de::Error::custom)?;
+
+    Ok(T::from(duration))
+}
+
+pub fn deserialize_signed_duration<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: From<SignedDuration>,
+{
+    let secs = String::deserialize(deserializer)?
+        .parse::<f64>()
+        .context("Error deserializing SignedDuration from string")
+        .map_err(serde::de::Error::custom)?;
+    let duration = SignedDuration::try_from_secs_f64(secs)
+        .context("Error deserializing SignedDuration from string")
+        .map_err(serde::de::Error::custom)?;
+
+    Ok(T::from(duration))
+}
+
+/// Determines the actual duration of the primary stream of `mi` by decoding it in full.
+pub fn decoded_duration(mi: &MediaInfo, tools: &Tools) -> Result<Duration> {
+    let ffmpeg = ffmpeg(tools)
+        .arg("-hide_banner")
+        .arg("-v")
+        .arg("error")
+        .arg("-nostats")
+        .arg("-i")
+        .arg(&mi.path)
+        .arg("-map")
+        .arg(format!("0:{}", mi.stream_index))
+        .arg("-progress")
+        .arg("pipe:1")
+        .arg("-f")
+        .arg("null")
+        .arg("-")
+        .output()
+        .context("Error running ffmpeg!")?;
+
+    if !ffmpeg.status.success() {
+        log_stderr(&ffmpeg.stderr);
+        bail!("Error decoding media");
+    }
+
+    // The progress report is a series of key=value lines, the last of which describe the
+    // final position.
+    let out_time_us = String::from_utf8_lossy(&ffmpeg.stdout)
+        .lines()
+        .filter_map(|line| line.strip_prefix("out_time_us="))
+        .filter_map(|us| us.trim().parse::<u64>().ok())
+        .next_back();
+
+    match out_time_us {
+        Some(us) => Ok(Duration::from_micros(us)),
+        None => bail!("No progress reported by ffmpeg"),
+    }
+}
+
+/// The BLAKE3 hash of the contents of `path`, in lowercase hex, as computed by `b3sum`.
+pub fn content_hash(path: &Path) -> Result<String> {
+    let b3sum = Command::new("b3sum")
+        .arg("--no-names")
+        .arg(path)
+        .output()
+        .context("Error running b3sum!")?;
+    if !b3sum.status.success() {
+        log_stderr(&b3sum.stderr);
+        bail!("Error hashing {}", path.display());
+    }
+
+    let stdout = String::from_utf8_lossy(&b3sum.stdout);
+    match stdout.split_whitespace().next() {
+        Some(hash) => Ok(hash.to_ascii_lowercase()),
+        None => bail!("b3sum reported no hash for {}", path.display()),
+    }
+}
+
+/// Decodes `path` in full, returning the errors (if any) ffmpeg reports decoding it.
+pub fn decode_errors(path: &Path, tools: &Tools) -> Result<Vec<String>> {
+    let ffmpeg = ffmpeg(tools)
+        .arg("-hide_banner")
+        .arg("-nostats")
+        .arg("-v")
+        .arg("error")
+        .arg("-i")
+        .arg(path)
+        .arg("-f")
+        .arg("null")
+        .arg("-")
+        .output()
+        .context("Error running ffmpeg!")?;
+
+    let stderr = String::from_utf8_lossy(&ffmpeg.stderr);
+    let errors: Vec<_> = stderr
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_owned)
+        .collect();
+    if !ffmpeg.status.success() && errors.is_empty() {
+        bail!("Error decoding {}: {}", path.display(), ffmpeg.status);
+    }
+
+    Ok(errors)
+}
+
+/// The ffmpeg and ffprobe binaries run, see [`GroupOptions::tools`],
+/// [`MergeOptions::tools`], and [`ThumbnailOptions::tools`].
+#[derive(Debug, Clone)]
+pub struct Tools {
+    pub ffmpeg: PathBuf,
+    pub ffprobe: PathBuf,
+}
+
+impl Tools {
+    /// `ffmpeg` and `ffprobe` from the `PATH`, unless overridden by the `INSTAGROUPER_FFMPEG`
+    /// and `INSTAGROUPER_FFPROBE` environment variables.
+    pub fn from_env() -> Self {
+        let var = |name, default| {
+            std::env::var_os(name).map_or_else(|| PathBuf::from(default), PathBuf::from)
+        };
+        Tools {
+            ffmpeg: var("INSTAGROUPER_FFMPEG", "ffmpeg"),
+            ffprobe: var("INSTAGROUPER_FFPROBE", "ffprobe"),
+        }
+    }
+}
+
+impl Default for Tools {
+    fn default() -> Self {
+        Tools::from_env()
+    }
+}
+
+static TEMP_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Sets the directory temporary files are written to, instead of [`std::env::temp_dir()`].
+/// Fails if it's already set, i.e. if this was called before or a temporary file has already
+/// been written.
+pub fn set_temp_dir(dir: PathBuf) -> Result<()> {
+    TEMP_DIR
+        .set(dir)
+        .map_err(|_| anyhow::anyhow!("The temporary directory is already set"))
+}
+
+/// The directory temporary files are written to, see [`set_temp_dir()`].
+pub fn temp_dir() -> &'static Path {
+    TEMP_DIR.get_or_init(std::env::temp_dir)
+}
+
+/// The play overlay written to the [`temp_dir()`] by [`play_overlay_path()`], if it has been.
+static PLAY_OVERLAY: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// The path of the play overlay icon [`thumbnail()`] draws, writing it on first use.
+///
+/// The overlay is written once per process and reused by every thumbnail taken after, until
+/// [`remove_temp_files()`]. It's named after the hash of its content, so a different overlay
+/// (e.g. of another version) is never mistaken for it, and the process, so concurrent runs
+/// sharing the temporary directory neither write over nor remove one another's.
+fn play_overlay_path() -> Result<PathBuf> {
+    let mut play_overlay = PLAY_OVERLAY.lock().unwrap();
+    if let Some(path) = &*play_overlay {
+        return Ok(path.clone());
+    }
+
+    let hash = fnv_hash([play_overlay_webp()]);
+    let path = temp_dir().join(format!(
+        "instagrouper-play-overlay-{hash}-{}.webp",
+        std::process::id()
+    ));
+    std::fs::write(&path, play_overlay_webp())
+        .with_context(|| format!("Error writing play overlay to {}", path.display()))?;
+    Ok(play_overlay.insert(path).clone())
+}
+
+/// Removes the temporary files written by this process, to be called before it exits.
+pub fn remove_temp_files() {
+    if let Some(play_overlay) = PLAY_OVERLAY.lock().unwrap().take()
+        && let Err(err) = std::fs::remove_file(&play_overlay)
+    {
+        warn!(
+            "Error cleaning up play overlay icon at {}: {err}",
+            play_overlay.display()
+        );
+    }
+}
+
+#[test]
+fn play_overlay_reused() {
+    let path = play_overlay_path().unwrap();
+    assert_eq!(play_overlay_path().unwrap(), path);
+    assert_eq!(std::fs::read(&path).unwrap(), play_overlay_webp());
+    remove_temp_files();
+    assert!(!path.exists());
+}
+
+/// Logs the (stderr) output of a failed ffmpeg, ffprobe, or b3sum run for diagnosis, as
+/// the error it fails with only summarizes what went wrong.
+fn log_stderr(output: &[u8]) {
+    let output = String::from_utf8_lossy(output);
+    if !output.trim().is_empty() {
+        error!("{}", output.trim_end());
+    }
+}
+
+fn ffmpeg(tools: &Tools) -> Command {
+    Command::new(&tools.ffmpeg)
+}
+
+/// The state of an ffmpeg run, as reported block by block on its `-progress` output.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct FfmpegProgress {
+    /// How much of the output has been written
+    out_time: Option<Duration>,
+    /// The speed relative to real time, e.g. `2.5` for `2.5x`
+    speed: Option<f64>,
+}
+
+impl FfmpegProgress {
+    /// Updates the progress with a `key=value` `line` of `-progress` output, returning whether
+    /// it ends a block (i.e. the progress is up to date).
+    fn update(&mut self, line: &str) -> bool {
+        let Some((key, value)) = line.trim().split_once('=') else {
+            return false;
+        };
+        match key {
+            // Not `out_time_ms`, which is also in microseconds
+            "out_time_us" => {
+                self.out_time = value
+                    .parse::<i64>()
+                    .ok()
+                    .map(|us| Duration::from_micros(us.max(0) as u64));
             }
+            "speed" => self.speed = value.trim().trim_end_matches('x').parse().ok(),
+            "progress" => return true,
+            _ => (),
         }
+        false
     }
+}
 
-    let ffmpeg = Command::new("ffmpeg")
-        .arg("-hide_banner")
-        // .arg("-v")
-        // .arg("error")
-        .arg("-ss")
-        .arg(start)
-        .arg("-i")
-        .arg(src)
-        // Loop the image so it's always available at the same timestamp as the video
-        .arg("-loop")
-        .arg("1")
-        .arg("-i")
-        .arg(&play_overlay)
-        .arg("-filter_complex")
-        .arg(ffmpeg_vf(FfVersion::Compat))
-        .arg("-frames:v")
-        .arg("1")
-        .arg("-c:v")
-        .arg("mjpeg")
-        // .arg("-q:v")
-        // .arg("2")
-        .arg("-f")
-        .arg("image2")
-        .arg(out)
-        .output()
-        .context("Error running ffmpeg!")?;
+#[test]
+fn ffmpeg_progress() {
+    let mut progress = FfmpegProgress::default();
+    let block = "frame=120\nfps=60.00\nout_time_us=4000000\nout_time_ms=4000000\n\
+                 out_time=00:00:04.000000\nspeed=2.01x\nprogress=continue";
+    let ends: Vec<_> = block.lines().map(|line| progress.update(line)).collect();
+    assert_eq!(ends, [false, false, false, false, false, false, true]);
+    assert_eq!(progress.out_time, Some(Duration::from_secs(4)));
+    assert_eq!(progress.speed, Some(2.01));
 
-    if !ffmpeg.status.success() {
-        let mut stderr = std::io::stderr().lock();
-        let _ = stderr.write_all(&ffmpeg.stderr);
-        bail!("Error taking screenshot");
-    }
+    // Before any output is written
+    assert!(!progress.update("out_time_us=N/A"));
+    assert!(!progress.update("speed=N/A"));
+    assert!(progress.update("progress=end"));
+    assert_eq!(progress, FfmpegProgress::default());
+}
 
-    if !out.exists() {
-        std::io::stderr().lock().write_all(&ffmpeg.stderr).unwrap();
-        std::io::stdout().lock().write_all(&ffmpeg.stdout).unwrap();
-        bail!("Failed to generate screenshot with ffmpeg, refer to output.");
+/// Runs `ffmpeg`, passing its progress to `report` as it's made (after each block of
+/// `-progress` output on its stdout), and otherwise returning its status and stderr as
+/// [`Command::output()`] does (with an empty stdout).
+fn run_with_progress(
+    ffmpeg: &mut Command,
+    mut report: impl FnMut(FfmpegProgress),
+) -> std::io::Result<std::process::Output> {
+    use std::io::{BufRead, BufReader, Read};
+    use std::process::Stdio;
+
+    let mut child = ffmpeg
+        .arg("-progress")
+        .arg("pipe:1")
+        .arg("-nostats")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Drain stderr alongside stdout, so ffmpeg never blocks on either filling up
+    let mut stderr = child.stderr.take().unwrap();
+    let stderr = std::thread::spawn(move || {
+        let mut buf = Vec::new();
+        stderr.read_to_end(&mut buf).map(|_| buf)
+    });
+
+    let mut progress = FfmpegProgress::default();
+    for line in BufReader::new(child.stdout.take().unwrap()).lines() {
+        if progress.update(&line?) {
+            report(progress);
+        }
     }
 
-    let fname = out.file_name().unwrap();
-    eprintln!("Screenshot saved to {}", fname.display());
+    let status = child.wait()?;
+    Ok(std::process::Output {
+        status,
+        stdout: Vec::new(),
+        stderr: stderr.join().unwrap()?,
+    })
+}
+
+fn ffprobe(tools: &Tools) -> Command {
+    Command::new(&tools.ffprobe)
+}
 
+/// Verifies ffmpeg and ffprobe can be run at all, so a missing binary is reported upfront
+/// rather than as the failure of each input.
+pub fn check_tools(tools: &Tools) -> Result<()> {
+    for (name, path) in [("ffmpeg", &tools.ffmpeg), ("ffprobe", &tools.ffprobe)] {
+        tool_version(path).with_context(|| format!("{name} not found at {}", path.display()))?;
+    }
     Ok(())
 }
 
-#[derive(Debug, Hash, PartialEq, Eq, Clone)]
-pub struct MediaInfo {
-    pub stream_count: u8,
-    pub media: MediaType,
-    pub path: PathBuf,
-    pub codec: String,
-    pub size: u64,
-    pub duration: Duration,
-    pub timestamp: Timestamp,
-    pub resolution: Option<Resolution>,
-    pub bit_rate: Option<u32>,
+pub fn identify(path: &Path, tools: &Tools) -> Result<MediaInfo> {
+    parse_ffprobe(path, &probe(path, tools)?)
 }
 
-impl MediaInfo {
-    pub fn is_audio(&self) -> bool {
-        self.media == MediaType::Audio
-    }
+/// The raw JSON output of `ffprobe` for `path`, as [`identify()`] parses it.
+pub fn probe(path: &Path, tools: &Tools) -> Result<Vec<u8>> {
+    let ffprobe = ffprobe(tools)
+        .arg("-hide_banner")
+        .arg("-print_format")
+        .arg("json")
+        .arg("-show_format")
+        .arg("-show_entries")
+        .arg("stream")
+        .arg("-v")
+        .arg("error")
+        .arg(path)
+        .output()
+        .context("Error running ffprobe!")?;
 
-    pub fn is_video(&self) -> bool {
-        self.media == MediaType::Video
+    let stderr = String::from_utf8_lossy(&ffprobe.stderr);
+    if !ffprobe.status.success() {
+        bail!("Error analyzing media: {}", stderr.trim());
     }
-
-    pub fn is_image(&self) -> bool {
-        self.media == MediaType::Image
+    // e.g. truncated downloads may have ffprobe print warnings rather than JSON
+    if serde_json::from_slice::<serde::de::IgnoredAny>(&ffprobe.stdout).is_err() {
+        bail!("ffprobe output isn't valid JSON: {}", stderr.trim());
     }
+
+    Ok(ffprobe.stdout)
 }
 
-pub fn deserialize_duration<'de, D, T>(deserializer: D) -> Result<T, D::Error>
-where
-    D: serde::Deserializer<'de>,
-    T: From<Duration>,
-{
-    let secs = String::deserialize(deserializer)?
-        .parse::<f64>()
-        .context("Error deserializing Duration from string")
-        .map_err(serde::de::Error::custom)?;
+/// An input ffprobe could read, but that holds no media to speak of, e.g. a truncated download.
+/// [`group()`] skips such inputs rather than failing.
+#[derive(Debug)]
+pub struct BadMedia(pub &'static str);
 
-    Ok(T::from(Duration::from_secs_f64(secs)))
+impl Display for BadMedia {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
 }
 
-pub fn identify(path: &Path) -> Result<MediaInfo> {
+impl std::error::Error for BadMedia {}
+
+/// Builds the [`MediaInfo`] for `path` from the JSON output of `ffprobe`.
+fn parse_ffprobe(path: &Path, json: &[u8]) -> Result<MediaInfo> {
     #[derive(Debug, Deserialize)]
     pub struct Ffprobe {
+        /// Empty or missing for files that aren't media at all
+        #[serde(default)]
         pub format: Format,
+        #[serde(default)]
         pub streams: Vec<Stream>,
     }
 
-    #[derive(Debug, Deserialize)]
+    #[derive(Debug, Default, Deserialize)]
     pub struct Format {
-        pub size: String,
+        /// May be missing for remote inputs
+        pub size: Option<String>,
+        #[serde(default)]
         pub nb_streams: u8,
         /// Defaults to [`Duration::ZERO`] if field isn't present
         #[serde(default, deserialize_with = "deserialize_duration")]
@@ -403,101 +4494,614 @@ pub fn identify(path: &Path) -> Result<MediaInfo> {
     #[derive(Debug, Deserialize)]
     pub struct Tags {
         pub creation_time: Option<String>,
+        pub major_brand: Option<String>,
+        pub compatible_brands: Option<String>,
     }
 
     #[derive(Debug, Deserialize)]
     pub struct Stream {
+        pub index: usize,
         pub codec_type: String,
+        /// May be missing for data streams, e.g. timecodes
+        #[serde(default)]
         pub codec_name: String,
         pub width: Option<u16>,
         pub height: Option<u16>,
         pub bit_rate: Option<String>,
+        pub pix_fmt: Option<String>,
+        pub avg_frame_rate: Option<String>,
+        /// The base frame rate, which may be reported when the average isn't (as `0/0`)
+        pub r_frame_rate: Option<String>,
         /// Defaults to `None` if field isn't present
         #[serde(default, deserialize_with = "deserialize_duration")]
         pub duration: Option<Duration>,
+        /// Defaults to `None` if field isn't present
+        #[serde(default, deserialize_with = "deserialize_signed_duration")]
+        pub start_time: Option<SignedDuration>,
+        #[serde(default)]
+        pub disposition: Disposition,
+        #[serde(default)]
+        pub tags: StreamTags,
+        #[serde(default)]
+        pub side_data_list: Vec<SideData>,
     }
 
-    let ffprobe = Command::new("ffprobe")
-        .arg("-hide_banner")
-        .arg("-print_format")
-        .arg("json")
-        .arg("-show_format")
-        .arg("-show_entries")
-        .arg("stream")
-        .arg("-v")
-        .arg("error")
-        .arg(path)
-        .output()
-        .context("Error running ffprobe!")?;
+    impl Stream {
+        /// The rotation of the stream, from its display matrix or else its (legacy) `rotate`
+        /// tag, unless it's unrotated.
+        fn rotation(&self) -> Option<i16> {
+            let display_matrix = self
+                .side_data_list
+                .iter()
+                .find_map(|side_data| side_data.rotation);
+            // The display matrix rotates counter-clockwise, the tag clockwise
+            let rotation = match display_matrix {
+                Some(rotation) => rotation.round() as i16,
+                None => -self.tags.rotate.as_deref()?.trim().parse::<i16>().ok()?,
+            };
+            // e.g. -90 rather than 270
+            let rotation = match rotation.rem_euclid(360) {
+                rotation if rotation > 180 => rotation - 360,
+                rotation => rotation,
+            };
+            Some(rotation).filter(|&rotation| rotation != 0)
+        }
 
-    if !ffprobe.status.success() {
-        let mut stderr = std::io::stderr().lock();
-        let _ = stderr.write_all(&ffprobe.stderr);
-        bail!("Error analyzing media");
+        /// The displayed resolution of the stream, i.e. with its dimensions swapped if rotated
+        /// by a quarter turn.
+        fn resolution(&self) -> Option<Resolution> {
+            let (width, height) = self.width.zip(self.height)?;
+            let quarter_turn = self
+                .rotation()
+                .is_some_and(|rotation| rotation.rem_euclid(180) == 90);
+            Some(match quarter_turn {
+                true => Resolution {
+                    width: height,
+                    height: width,
+                },
+                false => Resolution { width, height },
+            })
+        }
+    }
+
+    #[derive(Debug, Default, Deserialize)]
+    pub struct StreamTags {
+        /// Usually an ISO 639-2 code, e.g. `eng`
+        pub language: Option<String>,
+        /// Set by older versions of ffmpeg instead of a display matrix
+        pub rotate: Option<String>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct SideData {
+        /// Set for the display matrix of rotated (e.g. portrait phone) videos
+        pub rotation: Option<f64>,
+    }
+
+    #[derive(Debug, Default, Deserialize)]
+    pub struct Disposition {
+        /// Set for embedded cover art, e.g. in `.mp3` or `.m4a` audio files
+        #[serde(default)]
+        pub attached_pic: u8,
     }
 
     let parser = jiff::fmt::temporal::DateTimeParser::new();
-    let ffprobe: Ffprobe =
-        serde_json::from_slice(&ffprobe.stdout).expect("Internal error decoding ffprobe output!");
+    let ffprobe: Ffprobe = serde_json::from_slice(json).context("Error decoding ffprobe output")?;
 
     if ffprobe.streams.is_empty() {
-        bail!("Empty media file provided (no streams)");
+        return Err(BadMedia("Empty media file provided (no streams)").into());
+    }
+    if ffprobe.format.nb_streams == 0 {
+        return Err(BadMedia("Empty media file provided (no format)").into());
     }
 
+    // The first stream needn't be the primary one, e.g. timecode (data) streams often come
+    // first, so pick it by type: video over audio over subtitles. Audio files may also carry
+    // cover art, which ffprobe reports as a (still image) video stream, possibly before the
+    // audio itself. Don't let it turn the file into an image, unless it's all there is.
+    let rank = |stream: &Stream| match stream.codec_type.as_str() {
+        "video" if stream.disposition.attached_pic == 0 => Some(0),
+        "audio" => Some(1),
+        "subtitle" => Some(2),
+        "video" => Some(3),
+        _ => None,
+    };
+    let primary = ffprobe
+        .streams
+        .iter()
+        .filter_map(|stream| Some((rank(stream)?, stream)))
+        .min_by_key(|&(rank, _)| rank)
+        .map(|(_, stream)| stream);
+    let Some(primary) = primary else {
+        return Err(BadMedia("No audio, video, or subtitle streams").into());
+    };
+
+    // Fragmented MP4s are identified by their brands, as ffprobe doesn't report fragmentation
+    let fragmented = ffprobe.format.tags.as_ref().is_some_and(|tags| {
+        let brands = tags.major_brand.iter().chain(&tags.compatible_brands);
+        brands
+            .flat_map(|brands| brands.as_bytes().chunks(4))
+            .any(|brand| {
+                matches!(
+                    brand,
+                    b"dash" | b"msdh" | b"msix" | b"iso5" | b"iso6" | b"cmfc" | b"cmf2"
+                )
+            })
+    });
+
+    // ffprobe reports e.g. `N/A` when it can't determine the bit rate
+    let parse_bit_rate = |bit_rate: &str| match bit_rate.parse() {
+        Ok(bit_rate) => Some(bit_rate),
+        Err(_) => {
+            debug!("{}: Ignoring unknown bit rate {bit_rate:?}", path.display());
+            None
+        }
+    };
+
+    let size = match &ffprobe.format.size {
+        Some(size) => size
+            .parse()
+            .with_context(|| format!("Invalid size {size:?} reported"))?,
+        None => 0,
+    };
+    let media = match primary.codec_type.as_str() {
+        "audio" => MediaType::Audio,
+        "video" if matches!(primary.codec_name.as_str(), "png" | "mjpeg" | "webp") => {
+            MediaType::Image
+        }
+        "video" => MediaType::Video,
+        "subtitle" => MediaType::Subtitle,
+        other => unreachable!("Unexpected media type {other} of stream {}", primary.index),
+    };
+    let timestamp = match ffprobe
+        .format
+        .tags
+        .as_ref()
+        .and_then(|t| t.creation_time.as_ref())
+    {
+        Some(ctime) => parser
+            .parse_timestamp(ctime)
+            .with_context(|| format!("Invalid creation time {ctime:?} reported"))?,
+        None => path
+            .metadata()
+            .and_then(|md| md.created())
+            .unwrap_or_else(|_| SystemTime::now())
+            .try_into()
+            .context("Invalid file creation time")?,
+    };
+
     let mut media_info = MediaInfo {
         path: path.to_owned(),
         stream_count: ffprobe.format.nb_streams,
-        size: ffprobe.format.size.parse().expect("Failed to parse size"),
-        media: match ffprobe.streams[0].codec_type.as_str() {
-            "audio" => MediaType::Audio,
-            "video"
-                if matches!(
-                    ffprobe.streams[0].codec_name.as_str(),
-                    "png" | "mjpeg" | "webp"
-                ) =>
-            {
-                MediaType::Image
-            }
-            "video" => MediaType::Video,
-            other => panic!("Unexpected media type {other}"),
-        },
-        codec: ffprobe.streams[0].codec_name.clone(),
-        duration: ffprobe.streams[0]
-            .duration
-            .unwrap_or(ffprobe.format.duration),
-        bit_rate: ffprobe.streams[0]
+        stream_index: primary.index,
+        size,
+        media,
+        codec: primary.codec_name.clone(),
+        duration: primary.duration.unwrap_or(ffprobe.format.duration),
+        bit_rate: primary
             .bit_rate
-            .as_ref()
-            .or(ffprobe.format.bit_rate.as_ref())
-            .map(|s| s.parse().expect("Failed to parse bitrate")),
-        timestamp: match ffprobe.format.tags.and_then(|t| t.creation_time) {
-            Some(ctime) => parser
-                .parse_timestamp(&ctime)
-                .expect("Failed to parse creation time"),
-            None => path
-                .metadata()
-                .and_then(|md| md.created())
-                .unwrap_or_else(|_| SystemTime::now())
-                .try_into()
-                .expect("Failed to convert SystemTime to Timestamp!"),
-        },
+            .as_deref()
+            .and_then(parse_bit_rate)
+            .or_else(|| ffprobe.format.bit_rate.as_deref().and_then(parse_bit_rate)),
+        timestamp,
         resolution: None,
+        pix_fmt: primary.pix_fmt.clone(),
+        start_time: primary.start_time,
+        fragmented,
+        // `und` is ffmpeg's way of saying it's unknown
+        language: primary
+            .tags
+            .language
+            .clone()
+            .filter(|language| language != "und"),
+        frame_rate: None,
+        rotation: None,
+        fingerprint: None,
+        streams: ffprobe
+            .streams
+            .iter()
+            .map(|stream| StreamInfo {
+                index: stream.index,
+                kind: stream.codec_type.clone(),
+                codec: stream.codec_name.clone(),
+                resolution: stream.resolution(),
+                bit_rate: stream
+                    .bit_rate
+                    .as_deref()
+                    .and_then(|rate| rate.parse().ok()),
+                language: stream.tags.language.clone().filter(|lang| lang != "und"),
+            })
+            .collect(),
     };
     if matches!(media_info.media, MediaType::Video | MediaType::Image) {
-        media_info.resolution = Resolution {
-            width: ffprobe.streams[0].width.unwrap(),
-            height: ffprobe.streams[0].height.unwrap(),
-        }
-        .into();
+        let Some(resolution) = primary.resolution() else {
+            bail!("No resolution reported for stream {}", primary.index);
+        };
+        media_info.resolution = Some(resolution);
+    }
+    if media_info.is_video() {
+        media_info.rotation = primary.rotation();
+        media_info.frame_rate = [&primary.avg_frame_rate, &primary.r_frame_rate]
+            .into_iter()
+            .find_map(|rate| rate.as_deref()?.parse().ok());
     }
 
     Ok(media_info)
 }
 
+#[test]
+fn parse_ffprobe_na_bit_rate() {
+    let json = include_bytes!("../tests/fixtures/ffprobe_na_bitrate.json");
+    let info = parse_ffprobe(Path::new("na_bitrate.webm"), json).unwrap();
+    assert_eq!(info.media, MediaType::Video);
+    assert_eq!(info.bit_rate, None);
+    assert_eq!(
+        info.frame_rate,
+        Some(FrameRate {
+            num: 30000,
+            den: 1001
+        })
+    );
+    assert_eq!(info.duration, Duration::from_millis(12_345));
+
+    // Images and some streams report no average frame rate, only a base one (or neither)
+    let json = std::str::from_utf8(json).unwrap();
+    let parse = |avg_frame_rate: &str| {
+        let json = json.replace(r#""30000/1001""#, avg_frame_rate);
+        parse_ffprobe(Path::new("na_bitrate.webm"), json.as_bytes()).unwrap()
+    };
+    assert_eq!(
+        parse(r#""0/0", "r_frame_rate": "25/1""#).frame_rate,
+        Some(FrameRate { num: 25, den: 1 })
+    );
+    assert_eq!(parse(r#""0/0", "r_frame_rate": "0/0""#).frame_rate, None);
+    assert_eq!(
+        info.resolution,
+        Some(Resolution {
+            width: 1280,
+            height: 720
+        })
+    );
+}
+
+#[test]
+fn parse_ffprobe_fragmented() {
+    let json = include_bytes!("../tests/fixtures/ffprobe_fragmented.json");
+    let info = parse_ffprobe(Path::new("segment_audio.m4s"), json).unwrap();
+    assert_eq!(info.media, MediaType::Audio);
+    assert!(info.fragmented);
+    assert_eq!(info.streams.len(), 1);
+    assert_eq!(info.streams[0].kind, "audio");
+    assert_eq!(output_extension(&[info], &Default::default()), "mp4");
+
+    let json = include_bytes!("../tests/fixtures/ffprobe_na_bitrate.json");
+    let info = parse_ffprobe(Path::new("na_bitrate.webm"), json).unwrap();
+    assert!(!info.fragmented);
+}
+
+#[test]
+fn parse_ffprobe_rotation() {
+    let json = include_str!("../tests/fixtures/ffprobe_na_bitrate.json");
+    let parse = |json: &str| parse_ffprobe(Path::new("portrait.mp4"), json.as_bytes()).unwrap();
+    let portrait = Some(Resolution {
+        width: 720,
+        height: 1280,
+    });
+
+    let info = parse(&json.replacen(
+        r#""bit_rate": "N/A","#,
+        r#""side_data_list": [{"side_data_type": "Display Matrix", "rotation": -90}],"#,
+        1,
+    ));
+    assert_eq!(info.rotation, Some(-90));
+    assert_eq!(info.resolution, portrait);
+    assert_eq!(info.streams[0].resolution, portrait);
+
+    let info = parse(&json.replacen(r#""bit_rate": "N/A","#, r#""tags": {"rotate": "270"},"#, 1));
+    // Tagged clockwise
+    assert_eq!(info.rotation, Some(90));
+    assert_eq!(info.resolution, portrait);
+
+    // Upside down videos keep their orientation
+    let info = parse(&json.replacen(r#""bit_rate": "N/A","#, r#""tags": {"rotate": "180"},"#, 1));
+    assert_eq!(info.rotation, Some(180));
+    assert_eq!(info.resolution.unwrap().width, 1280);
+    assert_eq!(parse(json).rotation, None);
+}
+
+#[test]
+fn parse_ffprobe_stream_order() {
+    let json = include_bytes!("../tests/fixtures/ffprobe_stream_order.json");
+    let mut json: serde_json::Value = serde_json::from_slice(json).unwrap();
+    let mut parse = |drop_index: Option<usize>| {
+        let streams = json["streams"].as_array_mut().unwrap();
+        streams.retain(|stream| Some(stream["index"].as_u64().unwrap() as usize) != drop_index);
+        let json = serde_json::to_vec(&json).unwrap();
+        parse_ffprobe(Path::new("reel_1234_720p.mp4"), &json)
+    };
+
+    // The video, despite the timecode, cover art, and audio streams before it
+    let info = parse(None).unwrap();
+    assert_eq!(info.media, MediaType::Video);
+    assert_eq!(info.stream_index, 3);
+    assert_eq!(info.codec, "h264");
+    assert_eq!(info.stream_count, 4);
+    assert_eq!(info.streams.len(), 4);
+
+    // Then the audio, rather than its cover art
+    let info = parse(Some(3)).unwrap();
+    assert_eq!(info.media, MediaType::Audio);
+    assert_eq!(info.stream_index, 2);
+    assert_eq!(info.language.as_deref(), Some("eng"));
+
+    // Then the cover art, being all there is
+    let info = parse(Some(2)).unwrap();
+    assert_eq!(info.media, MediaType::Image);
+    assert_eq!(info.stream_index, 1);
+
+    let err = parse(Some(1)).unwrap_err();
+    assert!(err.downcast_ref::<BadMedia>().is_some());
+}
+
+#[test]
+fn parse_ffprobe_malformed() {
+    let parse = |json: &str| parse_ffprobe(Path::new("bad.mp4"), json.as_bytes());
+    let json = include_str!("../tests/fixtures/ffprobe_na_bitrate.json");
+    assert!(parse(json).is_ok());
+
+    let err = parse(&json.replace(r#""size": "1048576""#, r#""size": "N/A""#)).unwrap_err();
+    assert!(format!("{err:#}").contains("size"));
+    assert!(parse(&json.replace(r#""width": 1280,"#, "")).is_err());
+    assert!(parse(&json.replace(r#""video""#, r#""data""#)).is_err());
+    assert!(parse(&json.replace("2024-05-20T12", "yesterday")).is_err());
+    assert!(parse(&json.replace(r#""12.345000""#, r#""-1""#)).is_err());
+    assert!(parse("not json").is_err());
+
+    let is_bad_media = |json: &str| {
+        parse(json)
+            .unwrap_err()
+            .downcast_ref::<BadMedia>()
+            .is_some()
+    };
+    assert!(is_bad_media(r#"{"format": {}, "streams": []}"#));
+    assert!(is_bad_media("{}"));
+    let format = json.find(r#""format""#).unwrap();
+    assert!(is_bad_media(&format!(
+        r#"{}"format": {{}}}}"#,
+        &json[..format]
+    )));
+    assert!(!is_bad_media(&json.replace(r#""width": 1280,"#, "")));
+}
+
+#[test]
+fn media_info_round_trip() {
+    let json = include_bytes!("../tests/fixtures/ffprobe_na_bitrate.json");
+    let info = parse_ffprobe(Path::new("na_bitrate.webm"), json).unwrap();
+    let cached = serde_json::to_string(&info).unwrap();
+    assert!(cached.contains(r#""duration":"12.345000""#));
+    assert!(cached.contains(r#""resolution":"1280x720""#));
+    assert!(cached.contains(r#""frame_rate":"30000/1001""#));
+    assert_eq!(serde_json::from_str::<MediaInfo>(&cached).unwrap(), info);
+}
+
+/// The version `tool` (e.g. [`Tools::ffmpeg`]) reports, i.e. the first line of its `-version`
+/// output, such as `ffmpeg version 7.1 Copyright (c) 2000-2024 the FFmpeg developers`.
+pub fn tool_version(tool: &Path) -> Result<String> {
+    let output = Command::new(tool)
+        .arg("-hide_banner")
+        .arg("-version")
+        .output()
+        .with_context(|| format!("Error running {}", tool.display()))?;
+    if !output.status.success() {
+        bail!("{} -version failed: {}", tool.display(), output.status);
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    match stdout.lines().next() {
+        Some(version) => Ok(version.to_owned()),
+        None => bail!("{} -version reported nothing", tool.display()),
+    }
+}
+
+/// The version a utility other than ffmpeg (e.g. `zip`) reports when run with `arg` (e.g.
+/// `--version`), i.e. the first line of its output, to tell whether it can be run at all.
+pub fn utility_version(utility: &str, arg: &str) -> Result<String> {
+    let output = Command::new(utility)
+        .arg(arg)
+        .output()
+        .with_context(|| format!("Error running {utility}"))?;
+    if !output.status.success() {
+        bail!("{utility} {arg} failed: {}", output.status);
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    match stdout.lines().find(|line| !line.trim().is_empty()) {
+        Some(version) => Ok(version.trim().to_owned()),
+        None => bail!("{utility} {arg} reported nothing"),
+    }
+}
+
+/// The outcome of one of the [`self_test()`] checks.
+#[derive(Debug, Clone)]
+pub struct Check {
+    pub name: String,
+    /// What requires the checked capability
+    pub needed_by: &'static str,
+    pub passed: bool,
+    /// Details about the check's outcome, e.g. the version of the tool found
+    pub detail: Option<String>,
+}
+
+/// Verifies ffprobe and ffmpeg can be run and that ffmpeg supports the encoders, decoders,
+/// and filters we rely on.
+pub fn self_test(tools: &Tools) -> Vec<Check> {
+    fn version(tool: &str, version: Result<String>, needed_by: &'static str) -> Check {
+        let (passed, detail) = match version {
+            Ok(version) => (true, Some(version)),
+            Err(err) => (false, Some(format!("{err:#}"))),
+        };
+        Check {
+            name: tool.to_owned(),
+            needed_by,
+            passed,
+            detail,
+        }
+    }
+
+    /// Lists the names of the components `ffmpeg -{kind}` reports, e.g. `encoders`.
+    fn components(tools: &Tools, kind: &str) -> Option<Vec<String>> {
+        let output = ffmpeg(tools)
+            .arg("-hide_banner")
+            .arg(format!("-{kind}"))
+            .output()
+            .ok()
+            .filter(|output| output.status.success())?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        // Each component is listed as e.g. ` V....D mjpeg   MJPEG (Motion JPEG)`, after a
+        // legend separated from the list by a ` ------` line.
+        let names = stdout
+            .lines()
+            .skip_while(|line| !line.trim_start().starts_with("---"))
+            .skip(1)
+            .filter_map(|line| line.split_whitespace().nth(1))
+            .map(str::to_owned)
+            .collect();
+        Some(names)
+    }
+
+    let mut checks = vec![
+        version(
+            "ffprobe",
+            tool_version(&tools.ffprobe),
+            "identifying inputs",
+        ),
+        version(
+            "ffmpeg",
+            tool_version(&tools.ffmpeg),
+            "merging and thumbnails",
+        ),
+    ];
+    let ffmpeg_found = checks[1].passed;
+    for (utility, arg, needed_by) in [
+        ("zip", "-v", "--archive of a .zip"),
+        ("tar", "--version", "--archive of a .tar"),
+        ("b3sum", "--version", "--skip-hashes"),
+    ] {
+        checks.push(version(utility, utility_version(utility, arg), needed_by));
+    }
+    if !ffmpeg_found {
+        return checks;
+    }
+
+    let required: &[(&str, &[(&str, &'static str)])] = &[
+        (
+            "encoders",
+            &[
+                ("mjpeg", "thumbnails"),
+                ("libx264", "--pix-fmt"),
+                ("libwebp", "WebP thumbnails and --animated-preview"),
+                ("gif", "GIF thumbnails and animated previews"),
+            ],
+        ),
+        ("decoders", &[("webp", "thumbnail play overlay")]),
+        (
+            "filters",
+            &[
+                ("scale", "thumbnails"),
+                ("overlay", "thumbnails"),
+                ("scale2ref", "thumbnails of media of unknown resolution"),
+                ("pad", "--thumb-aspect and --thumb-size"),
+                ("crop", "--thumb-crop and --thumb-size"),
+                ("palettegen", "GIF animated previews"),
+                ("paletteuse", "GIF animated previews"),
+            ],
+        ),
+    ];
+
+    for &(kind, names) in required {
+        let available = components(tools, kind);
+        let singular = kind.strip_suffix('s').unwrap_or(kind);
+        for &(name, needed_by) in names {
+            let passed = available
+                .as_ref()
+                .is_some_and(|available| available.iter().any(|other| other == name));
+            checks.push(Check {
+                name: format!("{name} {singular}"),
+                needed_by,
+                passed,
+                detail: match available {
+                    None => Some(format!("Error listing ffmpeg {kind}")),
+                    Some(_) if !passed => Some(format!("ffmpeg lacks the {name} {singular}")),
+                    Some(_) => None,
+                },
+            });
+        }
+    }
+
+    checks
+}
+
+/// Adds the ffmpeg output options encoding a thumbnail in [`ThumbnailOptions::format`].
+fn thumbnail_encoder(ffmpeg: &mut Command, opts: &ThumbnailOptions) {
+    ffmpeg.arg("-c:v").arg(opts.format.encoder());
+    if opts.format == ThumbnailFormat::Jpeg
+        && let Some(quality) = opts.quality
+    {
+        ffmpeg.arg("-q:v").arg(quality.to_string());
+    }
+}
+
+/// Writes the bundled audio-only thumbnail to `out`, converted to [`ThumbnailOptions::format`].
+fn convert_audio_only_png(out: &Path, opts: &ThumbnailOptions) -> Result<()> {
+    let mut ffmpeg = ffmpeg(&opts.tools);
+    ffmpeg
+        .arg("-hide_banner")
+        .arg("-y")
+        .arg("-v")
+        .arg("error")
+        .arg("-f")
+        .arg("png_pipe")
+        .arg("-i")
+        .arg("-");
+    thumbnail_encoder(&mut ffmpeg, opts);
+    let mut child = ffmpeg
+        .arg("-frames:v")
+        .arg("1")
+        .arg("-f")
+        .arg("image2")
+        .arg(out)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .context("Error running ffmpeg!")?;
+    // ffmpeg reads all of its input before writing anything, so this can't deadlock
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(audio_only_png())
+        .context("Error piping audio-only thumbnail to ffmpeg")?;
+    let output = child.wait_with_output().context("Error running ffmpeg!")?;
+    if !output.status.success() {
+        bail!(
+            "Error converting audio-only thumbnail: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
 fn audio_only_png() -> &'static [u8] {
     include_bytes!("../media/audio-only.png")
 }
 
+/// The dimensions of [`play_overlay_webp()`]
+const PLAY_OVERLAY_RESOLUTION: Resolution = Resolution {
+    width: 512,
+    height: 512,
+};
+
 fn play_overlay_webp() -> &'static [u8] {
     include_bytes!("../media/play-overlay.webp")
 }
@@ -517,7 +5121,7 @@ impl fmt::Display for PrettyDuration {
             // hh:mm:ss.mmm
             write!(
                 f,
-                "{:02}:{:02}:{:02}.{:.3}",
+                "{:02}:{:02}:{:02}.{:03}",
                 hours, minutes, seconds, millis
             )
         } else if hours > 0 {
@@ -530,6 +5134,21 @@ impl fmt::Display for PrettyDuration {
     }
 }
 
+#[test]
+fn pretty_duration_millis() {
+    let pretty = |millis| format!("{:#}", PrettyDuration(Duration::from_millis(millis)));
+
+    assert_eq!(pretty(5), "00:00:00.005");
+    assert_eq!(pretty(50), "00:00:00.050");
+    assert_eq!(pretty(500), "00:00:00.500");
+    assert_eq!(pretty(3_723_045), "01:02:03.045");
+    // Only the alternate form shows milliseconds
+    assert_eq!(
+        PrettyDuration(Duration::from_millis(83_005)).to_string(),
+        "01:23"
+    );
+}
+
 impl Serialize for PrettyDuration {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -540,6 +5159,98 @@ impl Serialize for PrettyDuration {
     }
 }
 
+/// Parses the forms [`PrettyDuration`] is displayed in (`mm:ss`, `hh:mm:ss`, and
+/// `hh:mm:ss.mmm`) as well as a bare count of seconds, with or without leading zeros and with
+/// any number of fractional digits (down to nanoseconds).
+impl FromStr for PrettyDuration {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (whole, fraction) = match s.split_once('.') {
+            Some((whole, fraction)) => (whole, Some(fraction)),
+            None => (s, None),
+        };
+        let parts = whole.split(':').collect::<Vec<_>>();
+        if parts.len() > 3 {
+            bail!("Invalid duration {s}, expected e.g. 01:23, 1:02:03, or 01:02:03.456");
+        }
+
+        let mut secs = 0u64;
+        for (i, part) in parts.iter().enumerate() {
+            if part.is_empty() || !part.bytes().all(|b| b.is_ascii_digit()) {
+                bail!("Invalid duration {s}, {part:?} is not a number");
+            }
+            let value = part
+                .parse::<u64>()
+                .with_context(|| format!("Invalid duration {s}"))?;
+            // Only the leading component may overflow into the next larger unit
+            if i > 0 && value >= 60 {
+                bail!("Invalid duration {s}, {part} is out of range");
+            }
+            secs = secs
+                .checked_mul(60)
+                .and_then(|secs| secs.checked_add(value))
+                .with_context(|| format!("Invalid duration {s}, too long"))?;
+        }
+
+        let nanos = match fraction {
+            Some(fraction) => {
+                if fraction.is_empty() || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+                    bail!("Invalid duration {s}, {fraction:?} is not a fraction of a second");
+                }
+                // Digits past nanosecond precision are dropped
+                let digits = &fraction[..fraction.len().min(9)];
+                digits.parse::<u32>().unwrap() * 10u32.pow(9 - digits.len() as u32)
+            }
+            None => 0,
+        };
+
+        Ok(PrettyDuration(Duration::new(secs, nanos)))
+    }
+}
+
+impl<'de> Deserialize<'de> for PrettyDuration {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[test]
+fn parse_pretty_duration() {
+    let parse = |s: &str| s.parse::<PrettyDuration>().map(|d| d.0);
+    let ms = Duration::from_millis;
+
+    assert_eq!(parse("01:23").unwrap(), ms(83_000));
+    assert_eq!(parse("1:02:03").unwrap(), ms(3_723_000));
+    assert_eq!(parse("01:02:03.456").unwrap(), ms(3_723_456));
+    // Missing leading zeros and bare seconds
+    assert_eq!(parse("0:5").unwrap(), ms(5_000));
+    assert_eq!(parse("90").unwrap(), ms(90_000));
+    assert_eq!(parse("75:00").unwrap(), ms(4_500_000));
+    // Fewer or more than three fractional digits
+    assert_eq!(parse("00:00:01.5").unwrap(), ms(1_500));
+    assert_eq!(parse("1.000250").unwrap(), Duration::from_micros(1_000_250));
+    assert_eq!(
+        parse("0.1234567891").unwrap(),
+        Duration::from_nanos(123_456_789)
+    );
+
+    for garbage in [
+        "", ":", "1:", "1:60", "1:2:3:4", "1.", "1.5.5", "-1", "a:bc", " 1",
+    ] {
+        assert!(parse(garbage).is_err(), "{garbage:?} parsed");
+    }
+
+    let round_trip = PrettyDuration(ms(3_723_456));
+    let json = serde_json::to_string(&round_trip).unwrap();
+    assert_eq!(
+        serde_json::from_str::<PrettyDuration>(&json).unwrap(),
+        round_trip
+    );
+}
+
 impl From<Duration> for PrettyDuration {
     fn from(value: Duration) -> Self {
         Self(value)
@@ -1,31 +1,371 @@
-#![allow(special_module_name)]
-
-mod lib;
-
+use instagrouper as lib;
 use jiff::Timestamp;
 use serde::Serialize;
 use size::Size;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::{OsStr, OsString};
 use std::fmt::Display;
-use std::os::unix::ffi::OsStrExt;
-use std::path::PathBuf;
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::Duration;
 use uuid::Uuid;
 
 macro_rules! exit {
     ($($arg:tt)*) => {{
         eprintln!($($arg)*);
+        lib::remove_temp_files();
         std::process::exit(1);
     }};
 }
 
+/// Extensions of the input files we accept, compared case-insensitively.
+#[rustfmt::skip]
+const MEDIA_EXTENSIONS: &[&str] = &[
+    // Video (or audio+video) containers
+    "mp4", "m4v", "mov", "mkv", "webm",
+    // Audio-only containers
+    "m4a", "mp3", "wav", "aac", "ogg", "opus", "flac",
+    // Images, passed through as-is
+    "jpg", "jpeg", "png", "webp",
+];
+
+/// Extensions of the subtitle files we also accept with `--subtitles`
+const SUBTITLE_EXTENSIONS: &[&str] = &["vtt", "srt"];
+
+/// Whether `arg` is named with one of the `--ext` extensions (or else [`MEDIA_EXTENSIONS`],
+/// and [`SUBTITLE_EXTENSIONS`] with `--subtitles`), or has no extension at all, in which case
+/// it's left to ffprobe to tell if it's media.
+fn has_media_extension(arg: &OsStr, opts: &Options) -> bool {
+    let Some(ext) = Path::new(arg).extension() else {
+        return true;
+    };
+    let subtitles = match opts.group.subtitles {
+        true => SUBTITLE_EXTENSIONS,
+        false => &[],
+    };
+    match &opts.extensions {
+        Some(extensions) => extensions
+            .iter()
+            .any(|known| ext.eq_ignore_ascii_case(known)),
+        None => MEDIA_EXTENSIONS
+            .iter()
+            .chain(subtitles)
+            .any(|known| ext.eq_ignore_ascii_case(known)),
+    }
+}
+
+/// The inputs found by [`scan_dir()`]
+struct Scanned {
+    paths: Vec<PathBuf>,
+    /// The number of files skipped for lacking a media extension
+    skipped: usize,
+}
+
+/// Collects the files in directory `dir` (and, if `recursive`, its subdirectories) with a
+/// media extension, in name order. Unlike inputs named outright, files without an extension
+/// are skipped. Directories already in `visited` (by canonical path) are skipped too, so
+/// symlink loops are only followed once around.
+fn scan_dir(
+    dir: &Path,
+    recursive: bool,
+    opts: &Options,
+    visited: &mut BTreeSet<PathBuf>,
+) -> Scanned {
+    let mut scanned = Scanned {
+        paths: Vec::new(),
+        skipped: 0,
+    };
+    if !visited.insert(canonicalize(dir)) {
+        return scanned;
+    }
+
+    let entries =
+        std::fs::read_dir(dir).and_then(|entries| entries.map(|entry| Ok(entry?.path())).collect());
+    let mut entries: Vec<PathBuf> =
+        entries.unwrap_or_else(|err| exit!("Error reading directory {}: {err}", dir.display()));
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            if recursive {
+                let found = scan_dir(&path, recursive, opts, visited);
+                scanned.paths.extend(found.paths);
+                scanned.skipped += found.skipped;
+            }
+        } else if path.extension().is_some() && has_media_extension(path.as_os_str(), opts) {
+            scanned.paths.push(path);
+        } else {
+            scanned.skipped += 1;
+        }
+    }
+    scanned
+}
+
+/// Returns the value passed to option `opt`, which must be valid UTF-8.
+fn str_value(args: &mut impl Iterator<Item = OsString>, opt: &str) -> String {
+    args.next()
+        .or_exit(&format!("Missing {opt} value!"))
+        .into_string()
+        .ok()
+        .or_exit(&format!("Invalid {opt} value!"))
+}
+
 fn print_usage() {
-    eprintln!("instagrouper [--out-dir OUTDIR] path1.mp4 path2.mp4 ...");
+    eprintln!(
+        "\
+Usage: instagrouper [OPTIONS] path1.mp4 path2.mp4 ...
+       instagrouper [OPTIONS] [-r] DIR ...
+       instagrouper [OPTIONS] - < paths.txt
+       instagrouper --self-test
+       instagrouper [OPTIONS] --compare path1.mp4 path2.mp4
+
+Options:
+  -o, --out-dir OUTDIR        Write merged media and thumbnails to OUTDIR (default: ./)
+  --out-dir-av DIR            Write audio+video attachments to DIR instead
+  --out-dir-audio DIR         Write audio-only attachments to DIR instead
+  --out-dir-image DIR         Copy image attachments to DIR
+  --thumbnail-only            Only generate thumbnails for the inputs as-is
+  --no-thumbnail              Don't generate thumbnails
+  --thumb-limit N             Only generate thumbnails for the first N attachments
+  --paths-from FILE           Also process the inputs listed in FILE, one per line
+  --null                      Inputs listed with - or --paths-from are NUL-separated
+  -r, --recursive             Also scan the subdirectories of input directories
+  --ext EXT                   Accept inputs with extension EXT (repeatable)
+  --include-outputs           Don't skip inputs that look like previous outputs
+  --accurate-duration         Decode inputs in full to determine their durations
+  --group-key KEY             Group by duration, prefix, timestamp, or auto
+  --match duration|content    Also require grouped videos to look alike (content)
+  --interactive               Confirm ambiguous groupings
+  --live-photos               Pair images with short videos of the same name or time
+  --subtitles                 Accept subtitle files, merged with audio/video of their length
+  --max-delta SECONDS         Max duration difference of grouped inputs (default: 0.7)
+  --duration-buckets S,...    Group by the nearest of these durations, in seconds
+  --strict                    Refuse questionable groupings rather than warning about them
+  --min-resolution WxH        Skip videos below WxH when a larger one of their length exists
+  --concat-parts              Concatenate videos split into parts (_part1, _part2, ...)
+  --skip-hashes FILE          Skip inputs whose BLAKE3 hash is listed in FILE
+  -j, --jobs N                Run at most N ffprobe/ffmpeg processes at once
+  --max-inputs N              Group at most N inputs at once (default: {DEFAULT_MAX_INPUTS})
+  --pix-fmt FMT               Re-encode video to pixel format FMT if needed
+  --audio-lang LANG[,LANG]    Prefer audio in the given languages, in order
+  --sync                      Preserve the start time offset between audio and video
+  --trim-black                Trim black frames off the start and end of merged videos
+  --reencode never|auto|always
+                              When to re-encode streams the container can't hold
+                              (default: auto)
+  --container mp4|mkv|webm    The container of merged outputs (default: mp4)
+  --keep-all-resolutions      Create an attachment for every video resolution
+  --renumber                  Number attachments contiguously, without gaps
+  --name-template TEMPLATE    Name attachments after TEMPLATE, with placeholders {{stub}},
+                              {{index}}, {{date}}, {{resolution}}, and {{duration}}
+                              (default: {{stub}}_{{index}})
+  --thumb-at POS              Take thumbnails at POS, e.g. 30% or 00:07
+  --thumb-seek fast|accurate  How to seek to the thumbnail frame
+  --thumb-native              Don't downscale thumbnails of high resolution video
+  --hwaccel NAME              Decode video for thumbnails with ffmpeg -hwaccel NAME
+  --thumb-max-edge N          Downscale thumbnails to at most N pixels (default: {max_edge})
+  --thumb-size WxH            Scale thumbnails to exactly W by H pixels
+  --thumb-aspect W:H          Pad thumbnails to aspect ratio W:H
+  --thumb-crop                Crop thumbnails to --thumb-aspect instead of padding
+  --thumb-pad-color COLOR     Pad thumbnails with COLOR (default: black)
+  --thumb-filter FILTER       Pick the thumbnail frame with ffmpeg filter chain FILTER
+  --no-play-overlay           Don't draw the play icon over thumbnails of video
+  --animated-preview          Generate animated WebP previews instead of thumbnails
+  --thumb-format jpeg|png|webp|gif
+                              The image format of thumbnails (default: jpeg)
+  --thumb-quality Q           Encode JPEG thumbnails at quality Q, from 2 (best) to 31
+  --chmod MODE                Set the permissions of generated files to octal MODE
+  --preserve-mtime            Date generated files after their sources
+  --detect-duplicates         Flag attachments that look like duplicates
+  --dry-run                   Print the attachments that would be created, creating none
+  --force                     Overwrite existing outputs rather than refusing to
+  --verify-decode             Decode generated files in full to check for errors
+  --no-verify-duration        Don't check merged files are as long as their sources
+  --archive FILE              Bundle the outputs and their manifest into a .zip or .tar
+  --archive-remove            Delete the outputs once bundled with --archive
+  --passthrough FILE          Merge the inputs as-is into FILE, without grouping them
+  --probe-streams WHICH       List the primary (default) or all streams of each source
+  --cache DIR                 Cache the identification of inputs in DIR
+  --dump-ffprobe DIR          Write the raw ffprobe output for each input to DIR
+  --temp-dir DIR              Write temporary files to DIR instead of the system's
+  --relative-to DIR           Output paths relative to DIR
+  --summary                   Wrap the output in an object with a summary of the run
+  --format json|ndjson|csv    The format of the output (default: json)
+  -q, --quiet                 Only print errors, without progress or warnings
+  -v, --verbose               Also print debugging diagnostics
+  --self-test                 Check ffmpeg and ffprobe support all features
+  --ffmpeg PATH               The ffmpeg binary to run (default: $INSTAGROUPER_FFMPEG)
+  --ffprobe PATH              The ffprobe binary to run (default: $INSTAGROUPER_FFPROBE)
+  --compare A B               Explain whether and why A and B would be grouped
+  -V, --version               Print the versions of instagrouper, ffmpeg, and ffprobe
+  -h, --help                  Print this help",
+        max_edge = lib::DEFAULT_THUMBNAIL_MAX_EDGE,
+    );
 }
 
-fn main() {
+/// Prints our version, along with that of the ffmpeg and ffprobe found.
+fn print_version(tools: &lib::Tools) {
+    println!("instagrouper {}", env!("CARGO_PKG_VERSION"));
+    for (tool, path) in [("ffmpeg", &tools.ffmpeg), ("ffprobe", &tools.ffprobe)] {
+        match lib::tool_version(path) {
+            Ok(version) => println!("{tool}: {version}"),
+            Err(err) => println!("{tool}: not available ({err:#})"),
+        }
+    }
+}
+
+/// Options parsed from the command line
+struct Options {
+    out_dir: PathBuf,
+    /// Overrides `out_dir` for `audio+video` attachments
+    out_dir_av: Option<PathBuf>,
+    /// Overrides `out_dir` for `audio` attachments
+    out_dir_audio: Option<PathBuf>,
+    /// Overrides `out_dir` for `image` attachments, which are copied there
+    out_dir_image: Option<PathBuf>,
+    /// The extensions of the inputs to accept, instead of [`MEDIA_EXTENSIONS`]
+    extensions: Option<Vec<String>>,
+    thumbnail_only: bool,
+    /// Skip generating thumbnails altogether
+    no_thumbnail: bool,
+    /// Only generate thumbnails for this many of the first attachments
+    thumb_limit: Option<usize>,
+    /// Generate animated WebP previews in place of still thumbnails
+    animated_preview: bool,
+    include_outputs: bool,
+    keep_all_resolutions: bool,
+    /// Number attachments contiguously rather than after the position of their group
+    renumber: bool,
+    /// What to name merged attachments
+    name_template: lib::NameTemplate,
+    detect_duplicates: bool,
+    /// Report the attachments that would be created without merging or thumbnailing anything
+    dry_run: bool,
+    /// Overwrite existing outputs rather than refusing to
+    force: bool,
+    /// Decode every generated file in full to check it for errors
+    verify_decode: bool,
+    summary: bool,
+    /// The format the results are printed in
+    format: OutputFormat,
+    /// Don't report progress or print warnings (which are still listed in the summary), or
+    /// anything but errors
+    quiet: bool,
+    /// Also print debugging diagnostics, see [`init_logger()`]
+    verbose: bool,
+    /// Permissions to apply to generated files
+    chmod: Option<u32>,
+    /// Set the modification time of generated files to the timestamp of their sources
+    preserve_mtime: bool,
+    /// Content hashes (see [`lib::content_hash()`]) of inputs to skip
+    skip_hashes: Option<BTreeSet<String>>,
+    /// The most inputs to group at once, see [`batches()`]
+    max_inputs: usize,
+    /// The file to merge all inputs into as-is, instead of grouping them
+    passthrough: Option<PathBuf>,
+    /// Inputs to explain the grouping of, instead of processing any inputs
+    compare: Option<[PathBuf; 2]>,
+    /// A `.zip` or `.tar` file to bundle the outputs into, see [`write_archive()`]
+    archive: Option<PathBuf>,
+    /// Delete the bundled outputs once archived
+    archive_remove: bool,
+    /// List every stream of each source in the output
+    all_streams: bool,
+    /// The (canonical) directory to output paths relative to
+    relative_to: Option<PathBuf>,
+    group: lib::GroupOptions,
+    merge: lib::MergeOptions,
+    thumbnail: lib::ThumbnailOptions,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            out_dir: PathBuf::from("./"),
+            out_dir_av: None,
+            out_dir_audio: None,
+            out_dir_image: None,
+            extensions: None,
+            thumbnail_only: false,
+            no_thumbnail: false,
+            thumb_limit: None,
+            animated_preview: false,
+            include_outputs: false,
+            keep_all_resolutions: false,
+            renumber: false,
+            name_template: lib::NameTemplate::default(),
+            detect_duplicates: false,
+            dry_run: false,
+            force: false,
+            verify_decode: false,
+            summary: false,
+            format: OutputFormat::Json,
+            quiet: false,
+            verbose: false,
+            chmod: None,
+            preserve_mtime: false,
+            skip_hashes: None,
+            max_inputs: DEFAULT_MAX_INPUTS,
+            passthrough: None,
+            compare: None,
+            all_streams: false,
+            archive: None,
+            archive_remove: false,
+            relative_to: None,
+            group: Default::default(),
+            merge: Default::default(),
+            thumbnail: Default::default(),
+        }
+    }
+}
+
+impl Options {
+    /// The extension of the thumbnails generated, see [`generate_thumbnail()`].
+    fn thumb_ext(&self) -> &'static str {
+        match self.animated_preview {
+            true => lib::ThumbnailFormat::Webp.extension(),
+            false => self.thumbnail.format.extension(),
+        }
+    }
+
+    /// The directory attachments of `kind` are written to.
+    fn out_dir(&self, kind: lib::MergeKind) -> &Path {
+        let dir = match kind {
+            lib::MergeKind::AudioVideo => &self.out_dir_av,
+            lib::MergeKind::Audio => &self.out_dir_audio,
+            lib::MergeKind::Image => &self.out_dir_image,
+            lib::MergeKind::Video => &None,
+        };
+        dir.as_deref().unwrap_or(&self.out_dir)
+    }
+
+    /// All the directories attachments may be written to.
+    fn out_dirs(&self) -> impl Iterator<Item = &Path> {
+        [&self.out_dir_av, &self.out_dir_audio, &self.out_dir_image]
+            .into_iter()
+            .flatten()
+            .map(PathBuf::as_path)
+            .chain([self.out_dir.as_path()])
+    }
+}
+
+/// Parses the command line into options, inputs, and the number of inputs skipped for not
+/// having a media extension.
+fn parse_args() -> (Options, Vec<PathBuf>, usize) {
     let mut args = std::env::args_os().skip(1);
     let mut paths = Vec::new();
-    let mut out_dir = PathBuf::from("./");
+    let mut candidates = Vec::new();
+    // Files listing further inputs, `None` being stdin
+    let mut lists = Vec::new();
+    let mut null = false;
+    let mut recursive = false;
+    let mut tools = lib::Tools::from_env();
+    let (mut version, mut run_self_test) = (false, false);
+    let mut verify_duration = true;
+    let mut interactive = false;
+    let mut skipped = 0;
+    let mut opts = Options::default();
 
     while let Some(arg) = args.next() {
         match arg.to_str() {
@@ -35,131 +375,1728 @@ fn main() {
                 if !path.exists() {
                     exit!("outdir not found!");
                 }
-                out_dir = path;
+                opts.out_dir = path;
+            }
+            Some(opt @ ("--out-dir-av" | "--out-dir-audio" | "--out-dir-image")) => {
+                let path = PathBuf::from(args.next().or_exit(&format!("Missing {opt} value!")));
+                if !path.exists() {
+                    exit!("{opt} not found!");
+                }
+                match opt {
+                    "--out-dir-av" => opts.out_dir_av = Some(path),
+                    "--out-dir-audio" => opts.out_dir_audio = Some(path),
+                    _ => opts.out_dir_image = Some(path),
+                }
+            }
+            Some("--archive") => {
+                let archive = PathBuf::from(args.next().or_exit("Missing --archive value!"));
+                if archive_tool(&archive).is_none() {
+                    exit!("Invalid --archive value, expected a .zip or .tar file!");
+                }
+                opts.archive = Some(archive);
+            }
+            Some("--archive-remove") => opts.archive_remove = true,
+            Some("--relative-to") => {
+                let base = args.next().or_exit("Missing --relative-to value!");
+                let base = std::fs::canonicalize(base).or_exit("Invalid --relative-to directory");
+                opts.relative_to = Some(base);
+            }
+            Some("--thumbnail-only") => opts.thumbnail_only = true,
+            Some("--no-thumbnail") => opts.no_thumbnail = true,
+            Some("--thumb-limit") => {
+                let limit = str_value(&mut args, "--thumb-limit");
+                opts.thumb_limit = Some(limit.parse().or_exit("Invalid --thumb-limit value!"));
+            }
+            Some("--ext") => {
+                let exts = str_value(&mut args, "--ext");
+                let exts = exts
+                    .split(',')
+                    .map(|ext| ext.trim().trim_start_matches('.'))
+                    .filter(|ext| !ext.is_empty());
+                let extensions = opts.extensions.get_or_insert_default();
+                extensions.extend(exts.map(str::to_owned));
+            }
+            Some("-") => lists.push(None),
+            Some("--paths-from") => {
+                let list = args.next().or_exit("Missing --paths-from value!");
+                lists.push(Some(PathBuf::from(list)));
+            }
+            Some("--null") => null = true,
+            Some("-r") | Some("--recursive") => recursive = true,
+            Some("--include-outputs") => opts.include_outputs = true,
+            Some("--keep-all-resolutions") => opts.keep_all_resolutions = true,
+            Some("--renumber") => opts.renumber = true,
+            Some("--name-template") => {
+                let template = str_value(&mut args, "--name-template");
+                opts.name_template = template.parse().or_exit("Invalid --name-template value");
+            }
+            Some("--summary") => opts.summary = true,
+            Some("--format") => {
+                opts.format = match str_value(&mut args, "--format").as_str() {
+                    "json" => OutputFormat::Json,
+                    "ndjson" => OutputFormat::Ndjson,
+                    "csv" => OutputFormat::Csv,
+                    other => exit!("Invalid --format value {other}, expected json, ndjson, or csv"),
+                };
+            }
+            Some("-q" | "--quiet") => opts.quiet = true,
+            Some("-v" | "--verbose") => opts.verbose = true,
+            Some("--detect-duplicates") => opts.detect_duplicates = true,
+            Some("--dry-run") => opts.dry_run = true,
+            Some("--force") => {
+                opts.force = true;
+                opts.merge.overwrite = true;
+                opts.thumbnail.overwrite = true;
+            }
+            Some("--verify-decode") => opts.verify_decode = true,
+            Some("--no-verify-duration") => verify_duration = false,
+            Some("--accurate-duration") => opts.group.accurate_duration = true,
+            Some("--live-photos") => opts.group.live_photos = true,
+            Some("--subtitles") => opts.group.subtitles = true,
+            Some("--strict") => opts.group.strict = true,
+            Some("-j" | "--jobs") => {
+                let jobs = str_value(&mut args, "--jobs");
+                let jobs = jobs.parse().ok().filter(|&jobs| jobs > 0);
+                opts.group.jobs = Some(jobs.or_exit("Invalid --jobs value!"));
+            }
+            Some("--max-delta") => {
+                opts.group.max_delta = str_value(&mut args, "--max-delta")
+                    .parse()
+                    .ok()
+                    .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+                    .or_exit("Invalid --max-delta value!");
+            }
+            Some("--probe-streams") => {
+                opts.all_streams = match str_value(&mut args, "--probe-streams").as_str() {
+                    "primary" => false,
+                    "all" => true,
+                    other => {
+                        exit!("Invalid --probe-streams value {other}, expected primary or all")
+                    }
+                };
+            }
+            Some("--cache") => {
+                let dir = PathBuf::from(args.next().or_exit("Missing --cache value!"));
+                std::fs::create_dir_all(&dir).or_exit("Error creating --cache directory");
+                opts.group.cache = Some(dir);
+            }
+            Some("--temp-dir") => {
+                let dir = PathBuf::from(args.next().or_exit("Missing --temp-dir value!"));
+                if !dir.is_dir() {
+                    exit!("--temp-dir directory not found!");
+                }
+                lib::set_temp_dir(dir).or_exit("Invalid --temp-dir");
+            }
+            Some("--dump-ffprobe") => {
+                let dir = PathBuf::from(args.next().or_exit("Missing --dump-ffprobe value!"));
+                if !dir.is_dir() {
+                    exit!("--dump-ffprobe directory not found!");
+                }
+                opts.group.dump_ffprobe = Some(dir);
+            }
+            Some("--duration-buckets") => {
+                let buckets = str_value(&mut args, "--duration-buckets");
+                opts.group.duration_buckets = buckets
+                    .split(',')
+                    .map(|secs| {
+                        secs.trim()
+                            .parse()
+                            .ok()
+                            .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+                            .filter(|bucket| !bucket.is_zero())
+                            .or_exit("Invalid --duration-buckets value!")
+                    })
+                    .collect();
+            }
+            Some("--interactive") => interactive = true,
+            Some("--group-key") => {
+                opts.group.key = match str_value(&mut args, "--group-key").as_str() {
+                    "duration" => lib::GroupKey::Duration,
+                    "prefix" => lib::GroupKey::Prefix,
+                    "timestamp" => lib::GroupKey::Timestamp,
+                    "auto" => lib::GroupKey::Auto,
+                    other => exit!(
+                        "Invalid --group-key value {other}, expected duration, prefix, timestamp, \
+                         or auto"
+                    ),
+                };
+            }
+            Some("--match") => {
+                opts.group.match_by = match str_value(&mut args, "--match").as_str() {
+                    "duration" => lib::MatchMode::Duration,
+                    "content" => lib::MatchMode::Content,
+                    other => exit!("Invalid --match value {other}, expected duration or content"),
+                };
+            }
+            Some("--concat-parts") => opts.group.concat_parts = true,
+            Some("--min-resolution") => {
+                let min = str_value(&mut args, "--min-resolution");
+                opts.group.min_resolution =
+                    Some(min.parse().or_exit("Invalid --min-resolution value"));
+            }
+            Some("--audio-lang") => {
+                let langs = str_value(&mut args, "--audio-lang");
+                let langs = langs
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|lang| !lang.is_empty());
+                opts.merge.audio_langs.extend(langs.map(str::to_owned));
+            }
+            Some("--sync") => opts.merge.sync = true,
+            Some("--trim-black") => opts.merge.trim_black = true,
+            Some("--reencode") => {
+                opts.merge.reencode = match str_value(&mut args, "--reencode").as_str() {
+                    "never" => lib::Reencode::Never,
+                    "auto" => lib::Reencode::Auto,
+                    "always" => lib::Reencode::Always,
+                    other => {
+                        exit!("Invalid --reencode value {other}, expected never, auto, or always")
+                    }
+                };
+            }
+            Some("--skip-hashes") => {
+                let file = args.next().or_exit("Missing --skip-hashes file!");
+                let hashes = std::fs::read_to_string(file).or_exit("Error reading --skip-hashes");
+                opts.skip_hashes = Some(parse_hashes(&hashes));
+            }
+            Some("--max-inputs") => {
+                opts.max_inputs = str_value(&mut args, "--max-inputs")
+                    .parse()
+                    .ok()
+                    .filter(|&max| max > 0)
+                    .or_exit("Invalid --max-inputs value!");
+            }
+            Some("--thumb-native") => opts.thumbnail.max_edge = None,
+            Some("--hwaccel") => opts.thumbnail.hwaccel = Some(str_value(&mut args, "--hwaccel")),
+            Some("--no-play-overlay") => opts.thumbnail.play_overlay = false,
+            Some("--animated-preview") => opts.animated_preview = true,
+            Some("--container") => {
+                let container = str_value(&mut args, "--container");
+                opts.merge.container = container.parse().or_exit("Invalid --container value");
+            }
+            Some("--thumb-format") => {
+                let format = str_value(&mut args, "--thumb-format");
+                opts.thumbnail.format = format.parse().or_exit("Invalid --thumb-format value");
+            }
+            Some("--thumb-quality") => {
+                let quality = str_value(&mut args, "--thumb-quality")
+                    .parse()
+                    .ok()
+                    .filter(|quality| (2..=31).contains(quality))
+                    .or_exit("Invalid --thumb-quality value, expected 2 (best) to 31 (worst)");
+                opts.thumbnail.quality = Some(quality);
+            }
+            Some("--thumb-max-edge") => {
+                let max = str_value(&mut args, "--thumb-max-edge")
+                    .parse::<u16>()
+                    .ok()
+                    .filter(|&max| max > 0)
+                    .or_exit("Invalid --thumb-max-edge value!");
+                opts.thumbnail.max_edge = Some(max);
+            }
+            Some("--thumb-size") => {
+                let size = str_value(&mut args, "--thumb-size");
+                let size: lib::Resolution = size.parse().or_exit("Invalid --thumb-size value");
+                if size.width == 0 || size.height == 0 {
+                    exit!("Invalid --thumb-size value, must not be zero");
+                }
+                opts.thumbnail.size = Some(size);
+            }
+            Some("--thumb-aspect") => {
+                let aspect = str_value(&mut args, "--thumb-aspect");
+                opts.thumbnail.aspect =
+                    Some(aspect.parse().or_exit("Invalid --thumb-aspect value"));
+            }
+            Some("--thumb-crop") => opts.thumbnail.fit = lib::AspectFit::Crop,
+            Some("--thumb-pad-color") => {
+                let color = str_value(&mut args, "--thumb-pad-color");
+                // Keep the color from breaking out of the filter graph
+                if color.is_empty()
+                    || !color
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || "#@._".contains(c))
+                {
+                    exit!("Invalid --thumb-pad-color value {color}");
+                }
+                opts.thumbnail.pad_color = color;
             }
+            Some("--thumb-filter") => {
+                let filter = str_value(&mut args, "--thumb-filter");
+                lib::validate_thumbnail_filter(&filter).or_exit("Invalid --thumb-filter value");
+                opts.thumbnail.filter = Some(filter);
+            }
+            Some("--thumb-at") => {
+                let position = str_value(&mut args, "--thumb-at");
+                opts.thumbnail.position = position.parse().or_exit("Invalid --thumb-at value");
+            }
+            Some("--thumb-seek") => {
+                opts.thumbnail.seek = match str_value(&mut args, "--thumb-seek").as_str() {
+                    "fast" => lib::ThumbnailSeek::Fast,
+                    "accurate" => lib::ThumbnailSeek::Accurate,
+                    other => exit!("Invalid --thumb-seek value {other}, expected fast or accurate"),
+                };
+            }
+            Some("--chmod") => {
+                let mode = str_value(&mut args, "--chmod");
+                let mode = u32::from_str_radix(&mode, 8)
+                    .ok()
+                    .filter(|&mode| mode <= 0o7777)
+                    .or_exit("Invalid --chmod value, expected an octal mode like 0640!");
+                opts.chmod = Some(mode);
+            }
+            Some("--preserve-mtime") => opts.preserve_mtime = true,
+            Some("--pix-fmt") => {
+                opts.merge.pix_fmt = Some(str_value(&mut args, "--pix-fmt"));
+            }
+            Some("--self-test") => run_self_test = true,
+            Some(tool @ ("--ffmpeg" | "--ffprobe")) => {
+                let path = PathBuf::from(args.next().or_exit(&format!("Missing {tool} value!")));
+                match tool {
+                    "--ffmpeg" => tools.ffmpeg = path,
+                    _ => tools.ffprobe = path,
+                }
+            }
+            Some("--passthrough") => {
+                let output = args.next().or_exit("Missing --passthrough output!");
+                opts.passthrough = Some(PathBuf::from(output));
+            }
+            Some("--compare") => {
+                let a = PathBuf::from(args.next().or_exit("Missing --compare paths!"));
+                let b = PathBuf::from(args.next().or_exit("Missing second --compare path!"));
+                opts.compare = Some([a, b]);
+            }
+            Some("-V" | "--version") => version = true,
             Some("-h" | "--help") => {
                 print_usage();
                 std::process::exit(0);
             }
             Some(opt) if opt.starts_with("-") => exit!("Unrecognized option {opt}"),
-            _ => {
-                if let Some(ext) = arg.as_bytes().last_chunk::<4>()
-                    && (true || &ext.to_ascii_lowercase() == b".mp4")
-                {
-                    let path = PathBuf::from(arg);
-                    if !path.exists() {
-                        exit!("{}: Path not found", path.display());
-                    }
-                    paths.push(path);
-                }
+            _ if lib::is_url(Path::new(&arg)) => paths.push(PathBuf::from(arg)),
+            // Checked once all of the --ext options are known
+            _ => candidates.push(arg),
+        }
+    }
+
+    if version {
+        print_version(&tools);
+        std::process::exit(0);
+    }
+    if run_self_test {
+        self_test(&tools);
+    }
+    opts.group.tools = tools.clone();
+    opts.merge.tools = tools.clone();
+    opts.thumbnail.tools = tools;
+
+    for list in lists {
+        let contents = match &list {
+            Some(path) => std::fs::read_to_string(path).or_exit("Error reading --paths-from"),
+            None => std::io::read_to_string(std::io::stdin()).or_exit("Error reading stdin"),
+        };
+        let separator = if null { '\0' } else { '\n' };
+        for line in contents.split(separator) {
+            // Tolerate CRLF line endings, but not stray whitespace in NUL-separated names
+            let line = if null {
+                line
+            } else {
+                line.trim_end_matches('\r')
+            };
+            if line.is_empty() {
+                continue;
             }
+            if lib::is_url(Path::new(line)) {
+                paths.push(PathBuf::from(line));
+            } else {
+                candidates.push(OsString::from(line));
+            }
+        }
+    }
+
+    let mut visited = BTreeSet::new();
+    for arg in candidates {
+        let path = Path::new(&arg);
+        if path.is_dir() {
+            let found = scan_dir(path, recursive, &opts, &mut visited);
+            skipped += found.skipped;
+            paths.extend(found.paths);
+        } else if has_media_extension(&arg, &opts) {
+            let path = PathBuf::from(arg);
+            if !path.exists() {
+                exit!("{}: Path not found", path.display());
+            }
+            paths.push(path);
+        } else {
+            skipped += 1;
+        }
+    }
+
+    // The same input may be listed several times, possibly by different paths
+    let mut seen = BTreeSet::new();
+    paths.retain(|path| seen.insert(canonicalize(path)));
+
+    if opts.thumbnail_only && opts.no_thumbnail {
+        exit!("--thumbnail-only and --no-thumbnail are mutually exclusive!");
+    }
+    if opts.thumbnail_only && opts.group.subtitles {
+        exit!("--thumbnail-only and --subtitles are mutually exclusive!");
+    }
+    if opts.dry_run && (opts.thumbnail_only || opts.passthrough.is_some()) {
+        exit!("--dry-run can't be combined with --thumbnail-only or --passthrough!");
+    }
+    if opts.thumbnail_only && opts.passthrough.is_some() {
+        exit!("--thumbnail-only and --passthrough are mutually exclusive!");
+    }
+    if opts.summary && opts.format == OutputFormat::Csv {
+        exit!("--summary can't be combined with --format csv!");
+    }
+
+    if interactive {
+        if std::io::stdin().is_terminal() && std::io::stderr().is_terminal() {
+            opts.group.confirm = Some(confirm_grouping);
+        } else if !opts.quiet {
+            // (The logger isn't set up until the options are parsed.)
+            eprintln!("Not running in a terminal, ignoring --interactive");
+        }
+    }
+
+    // Merged outputs are held to the same tolerance as the durations of their sources
+    opts.merge.verify_duration = verify_duration.then_some(opts.group.max_delta);
+    opts.merge.strict = opts.group.strict;
+
+    (opts, paths, skipped)
+}
+
+/// Removes temporary files (see [`lib::remove_temp_files()`]) once `main` returns or unwinds.
+struct TempFiles;
+
+impl Drop for TempFiles {
+    fn drop(&mut self) {
+        lib::remove_temp_files();
+    }
+}
+
+fn main() {
+    let _temp_files = TempFiles;
+    let (opts, mut paths, skipped) = parse_args();
+    init_logger(&opts);
+    let mut dropped = vec![(skipped, "without a media extension")];
+
+    if opts.compare.is_none() {
+        if paths.is_empty() && skipped > 0 {
+            no_inputs_remain(&dropped);
         }
+        if paths.is_empty() {
+            print_usage();
+            std::process::exit(EXIT_USAGE);
+        }
+    }
+
+    lib::check_tools(&opts.group.tools).unwrap_or_else(|err| exit!("{err:#}"));
+    // Rather than after everything has been merged
+    if let Some(archive) = &opts.archive {
+        let tool = archive_tool(archive).unwrap();
+        let arg = if tool == "zip" { "-v" } else { "--version" };
+        lib::utility_version(tool, arg)
+            .unwrap_or_else(|err| exit!("{tool} is required by --archive: {err:#}"));
+    }
+    if opts.skip_hashes.is_some() {
+        lib::utility_version("b3sum", "--version")
+            .unwrap_or_else(|err| exit!("b3sum is required by --skip-hashes: {err:#}"));
+    }
+    if let Some([a, b]) = &opts.compare {
+        compare(a, b, &opts);
     }
 
-    if paths.is_empty() {
-        print_usage();
-        exit!("");
+    let warnings = match opts.quiet {
+        true => lib::Warnings::quiet(),
+        false => lib::Warnings::default(),
+    };
+
+    // Fail fast on outputs that would be overwritten, before doing any work. (Numbered outputs
+    // never are, as they skip the numbers already taken.)
+    if !opts.dry_run {
+        refuse_clobbering(opts.archive.iter().chain(&opts.passthrough), opts.force);
+    }
+
+    if let Some(output) = &opts.passthrough {
+        let mut results = vec![passthrough_attachment(&paths, output, &opts, &warnings)];
+        if !opts.all_streams {
+            strip_streams(&mut results);
+        }
+        if let Some(base) = &opts.relative_to {
+            relativize(&mut results, base, &warnings);
+        }
+        print_results(
+            results,
+            opts.summary
+                .then(|| Summary::new(&paths, warnings.into_vec())),
+            opts.format,
+        );
+        return;
+    }
+
+    if opts.thumbnail_only {
+        let inputs = lib::par_map(&paths, opts.group.jobs, |_, path| {
+            lib::identify_with(path, &opts.group, &warnings)
+                .unwrap_or_else(|err| exit!("Error identifying {}: {err:#}", path.display()))
+        });
+        let thumbnails: Vec<_> = inputs
+            .iter()
+            .map(|mi| thumbnail_only_path(mi, &opts))
+            .collect();
+        refuse_clobbering(thumbnails.iter().flatten(), opts.force);
+
+        let mut results = lib::par_map(&inputs, opts.group.jobs, |n, mi| {
+            thumbnail_only_attachment(mi, thumbnails[n].as_deref(), &opts)
+        });
+        log::info!("Generated thumbnails for {} files", paths.len());
+        if let Some(archive) = &opts.archive {
+            write_archive(archive, &results, opts.archive_remove).or_exit("Error writing archive");
+        }
+        if !opts.all_streams {
+            strip_streams(&mut results);
+        }
+        if let Some(base) = &opts.relative_to {
+            relativize(&mut results, base, &warnings);
+        }
+        print_results(
+            results,
+            opts.summary
+                .then(|| Summary::new(&paths, warnings.into_vec())),
+            opts.format,
+        );
+        return;
+    }
+
+    if !opts.include_outputs {
+        let inputs = paths.len();
+        paths.retain(|path| {
+            let prior = opts
+                .out_dirs()
+                .any(|out_dir| is_prior_output(path, out_dir, &opts.name_template));
+            if prior {
+                warnings.warn(
+                    "prior-output",
+                    Some(path),
+                    format!(
+                        "Skipping {}: looks like a previous instagrouper output",
+                        path.display()
+                    ),
+                );
+            }
+            !prior
+        });
+        dropped.push((
+            inputs - paths.len(),
+            "previous outputs (pass --include-outputs to process them)",
+        ));
+        if paths.is_empty() {
+            no_inputs_remain(&dropped);
+        }
+    }
+
+    if let Some(skip_hashes) = &opts.skip_hashes {
+        let inputs = paths.len();
+        let hashes = lib::par_map(&paths, opts.group.jobs, |_, path| {
+            (!lib::is_url(path)).then(|| {
+                lib::content_hash(path)
+                    .unwrap_or_else(|err| exit!("Error hashing {}: {err:#}", path.display()))
+            })
+        });
+        let mut hashes = hashes.into_iter();
+        paths.retain(|path| {
+            let hash = hashes.next().unwrap();
+            let skip = hash.is_some_and(|hash| skip_hashes.contains(&hash));
+            if skip {
+                warnings.warn(
+                    "skipped-hash",
+                    Some(path),
+                    format!("Skipping {}: its content hash is listed", path.display()),
+                );
+            }
+            !skip
+        });
+        dropped.push((inputs - paths.len(), "with listed content hashes"));
+        if paths.is_empty() {
+            no_inputs_remain(&dropped);
+        }
     }
 
     // Group input files into groups matching a single original attachment
-    let groups = lib::group(&paths).unwrap();
-
-    let num_cpus = std::thread::available_parallelism()
-        .map(|n| n.get())
-        .unwrap_or(1);
-    let chunk_size = groups.len().div_ceil(num_cpus);
-
-    let results: Vec<Attachment> = std::thread::scope(|s| {
-        groups
-            .chunks(chunk_size)
-            .enumerate()
-            .map(|(chunk_idx, chunk)| {
-                let out_dir = out_dir.clone();
-                s.spawn(move || {
-                    let mut local_results = Vec::new();
-                    for (in_chunk_idx, group) in chunk.iter().enumerate() {
-                        assert!(!group.is_empty());
-                        let n = chunk_idx * chunk_size + in_chunk_idx;
-
-                        let timestamp = group.iter().map(|mi| mi.timestamp).min().unwrap();
-                        let sources = group.iter().map(|mi| mi.path.clone()).collect();
-                        let name0 = group[0].path.file_name().unwrap().to_string_lossy();
-
-                        if group.len() == 1 && group[0].is_image() {
-                            local_results.push(Attachment {
-                                name: name0.to_string(),
-                                size: group[0].size,
-                                size_pretty: Size::from_bytes(group[0].size).to_string(),
-                                timestamp,
-                                path: std::fs::canonicalize(&group[0].path).unwrap(),
-                                duration: Duration::ZERO.into(),
-                                kind: "image",
-                                sources,
-                                thumbnail: std::fs::canonicalize(&group[0].path).unwrap(),
-                            });
-                            continue;
-                        }
-
-                        // Try to use up to second _ as a prefix, new uuid otherwise.
-                        let uuid;
-                        let stub =
-                            if let Some(idx) = name0.match_indices('_').nth(1).map(|(i, _)| i) {
-                                &name0[..idx]
-                            } else {
-                                uuid = Uuid::now_v7().to_string();
-                                &uuid
-                            };
-
-                        let mp4name = format!("{stub}_{n:0>3}.mp4");
-                        let mp4path = out_dir.join(&mp4name);
-                        let kind = lib::merge(group, &mp4path).unwrap();
-
-                        let jpgname = format!("{stub}_{n:0>3}.jpg");
-                        let jpgpath = out_dir.join(jpgname);
-                        lib::thumbnail(&mp4path, &jpgpath).unwrap();
-
-                        let size = mp4path.metadata().unwrap().len();
-                        local_results.push(Attachment {
-                            name: mp4name,
-                            path: std::fs::canonicalize(mp4path).unwrap(),
-                            timestamp,
-                            size,
-                            size_pretty: Size::from_bytes(size).to_string(),
-                            kind,
-                            thumbnail: std::fs::canonicalize(&jpgpath).unwrap(),
-                            duration: group[0].duration.into(),
-                            sources,
-                        });
-                    }
-                    local_results
-                })
+    let mut groups = Vec::new();
+    let identifying = Counter::new("Identifying", paths.len(), &opts);
+    let progress = |event: lib::Event| {
+        if let lib::Event::Identified(_) | lib::Event::Error(..) = event {
+            identifying.tick();
+        }
+    };
+    for batch in batches(&paths, opts.max_inputs) {
+        let batch = lib::group_with_progress(&batch, &opts.group, &warnings, Some(&progress));
+        groups.extend(batch.unwrap_or_else(|err| exit!("{err:#}")));
+    }
+
+    // Each group is numbered after its position, unless renumbering the groups that are named
+    // after their number (i.e. all but passed-through images) contiguously.
+    let mut next = 0;
+    let numbers: Vec<usize> = groups
+        .iter()
+        .enumerate()
+        .map(|(n, group)| {
+            if !opts.renumber {
+                return n;
+            }
+            let n = next;
+            if !(group.len() == 1 && group[0].is_image()) {
+                next += 1;
+            }
+            n
+        })
+        .collect();
+
+    // Images are passed through under their own names
+    if let Some(out_dir) = &opts.out_dir_image
+        && !opts.dry_run
+    {
+        let images = groups
+            .iter()
+            .filter(|group| group.len() == 1 && group[0].is_image())
+            .map(|group| out_dir.join(lib::file_name(&group[0].path).unwrap_or_default()));
+        refuse_clobbering(images, opts.force);
+    }
+
+    // NDJSON is printed as each group is processed, unless duplicates are to be flagged, which
+    // takes all of them
+    let stream = opts.format == OutputFormat::Ndjson && !opts.detect_duplicates;
+    let merging = Counter::new("Merging", groups.len(), &opts);
+    let mut results: Vec<_> = lib::par_map(&groups, opts.group.jobs, |n, group| {
+        let thumbnail = !opts.no_thumbnail && opts.thumb_limit.is_none_or(|limit| n < limit);
+        let attachments = process_group(numbers[n], group, thumbnail, &opts, &warnings);
+        merging.tick();
+        if stream {
+            let mut printed = attachments.clone();
+            if !opts.all_streams {
+                strip_streams(&mut printed);
+            }
+            if let Some(base) = &opts.relative_to {
+                relativize(&mut printed, base, &warnings);
+            }
+            printed.iter().for_each(print_line);
+        }
+        attachments
+    })
+    .into_iter()
+    .flatten()
+    .collect();
+
+    // Nothing was written for a dry run to compare or archive
+    if opts.detect_duplicates && !opts.dry_run {
+        flag_duplicates(&mut results, &opts, &warnings);
+    }
+
+    if let Some(archive) = &opts.archive
+        && !opts.dry_run
+    {
+        write_archive(archive, &results, opts.archive_remove).or_exit("Error writing archive");
+    }
+
+    let attachments = results.len();
+    if stream {
+        // Already printed
+        results.clear();
+    }
+    if !opts.all_streams {
+        strip_streams(&mut results);
+    }
+    if let Some(base) = &opts.relative_to {
+        relativize(&mut results, base, &warnings);
+    }
+
+    let merged = if opts.dry_run {
+        "Would merge"
+    } else {
+        "Merged"
+    };
+    let thumbnails = if opts.no_thumbnail {
+        " (without thumbnails)"
+    } else {
+        ""
+    };
+    log::info!(
+        "{merged} {} files into {attachments} attachments{thumbnails}",
+        paths.len(),
+    );
+
+    print_results(
+        results,
+        opts.summary
+            .then(|| Summary::new(&paths, warnings.into_vec())),
+        opts.format,
+    );
+}
+
+/// The most bits the perceptual hashes of two attachments may differ by for them to be
+/// considered duplicates.
+const MAX_DUPLICATE_DISTANCE: u32 = 4;
+
+/// Sets [`Attachment::duplicate_of`] for attachments that look the same as an earlier one
+/// from a different group, going by the perceptual hashes of their thumbnail frames.
+fn flag_duplicates(attachments: &mut [Attachment], opts: &Options, warnings: &lib::Warnings) {
+    let hashes = lib::par_map(attachments, opts.group.jobs, |_, attachment| {
+        if attachment.kind == lib::MergeKind::Audio {
+            return None;
+        }
+        let (position, tools) = (opts.thumbnail.position, &opts.thumbnail.tools);
+        lib::perceptual_hash(&attachment.path, attachment.duration.0, position, tools)
+            .inspect_err(|err| {
+                log::warn!("Error hashing {}: {err:#}", attachment.path.display());
             })
-            .collect::<Vec<_>>()
-            .into_iter()
-            .flat_map(|handle| handle.join().unwrap())
-            .collect()
+            .ok()
     });
 
-    eprintln!(
-        "Merged {} files into {} attachments",
+    for i in 0..attachments.len() {
+        let Some(hash) = hashes[i] else {
+            continue;
+        };
+        let original = (0..i).find(|&j| {
+            attachments[j].group_id != attachments[i].group_id
+                && attachments[j].duplicate_of.is_none()
+                && hashes[j]
+                    .is_some_and(|other| lib::hash_distance(hash, other) <= MAX_DUPLICATE_DISTANCE)
+        });
+        if let Some(j) = original {
+            warnings.warn(
+                "duplicate",
+                Some(&attachments[i].path),
+                format!(
+                    "{} looks like a duplicate of {}",
+                    attachments[i].name, attachments[j].name
+                ),
+            );
+            attachments[i].duplicate_of = Some(attachments[j].group_id.clone());
+        }
+    }
+}
+
+/// The exit code when invoked without any inputs
+const EXIT_USAGE: i32 = 2;
+
+/// The exit code when none of the inputs passed remain to be processed after filtering
+const EXIT_NO_INPUTS: i32 = 3;
+
+/// The default `--max-inputs`
+const DEFAULT_MAX_INPUTS: usize = 5000;
+
+/// Prints the diagnostics the library logs to stderr as-is.
+struct StderrLogger;
+
+impl log::Log for StderrLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("{}", record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Prints the diagnostics the library logs to stderr: only errors with `--quiet`, everything
+/// with `--verbose`, and all but debugging diagnostics otherwise.
+fn init_logger(opts: &Options) {
+    static LOGGER: StderrLogger = StderrLogger;
+    let level = match (opts.quiet, opts.verbose) {
+        (true, _) => log::LevelFilter::Error,
+        (false, true) => log::LevelFilter::Debug,
+        (false, false) => log::LevelFilter::Info,
+    };
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(level);
+}
+
+/// Reports the progress of a step taken for many items on stderr (e.g. `Merging 37/142`),
+/// unless `--quiet` or stderr isn't a terminal.
+struct Counter {
+    label: &'static str,
+    total: usize,
+    done: AtomicUsize,
+    enabled: bool,
+}
+
+impl Counter {
+    fn new(label: &'static str, total: usize, opts: &Options) -> Self {
+        Counter {
+            label,
+            total,
+            done: AtomicUsize::new(0),
+            enabled: !opts.quiet && std::io::stderr().is_terminal(),
+        }
+    }
+
+    /// Counts another item done, overwriting the line reported for the previous one.
+    fn tick(&self) {
+        let done = self.done.fetch_add(1, Ordering::Relaxed) + 1;
+        if !self.enabled {
+            return;
+        }
+        let mut stderr = std::io::stderr().lock();
+        let _ = write!(stderr, "\r\x1b[K{} {done}/{}", self.label, self.total);
+        if done == self.total {
+            let _ = writeln!(stderr);
+        }
+    }
+}
+
+/// Splits `paths` into batches of at most `max_inputs` paths to be grouped independently of
+/// one another.
+///
+/// If there are too many inputs to group at once, they're batched by directory (on the
+/// assumption that files belonging to the same attachment are found alongside one another),
+/// exiting with an error if that's still not enough.
+fn batches(paths: &[PathBuf], max_inputs: usize) -> Vec<Vec<PathBuf>> {
+    if paths.len() <= max_inputs {
+        return vec![paths.to_vec()];
+    }
+
+    let mut by_dir: BTreeMap<&Path, Vec<PathBuf>> = BTreeMap::new();
+    for path in paths {
+        let dir = path.parent().unwrap_or(Path::new(""));
+        by_dir.entry(dir).or_default().push(path.clone());
+    }
+
+    for (dir, batch) in &by_dir {
+        if batch.len() > max_inputs {
+            exit!(
+                "Too many inputs: {} has {} files, more than the maximum of {max_inputs} \
+                 (see --max-inputs)",
+                dir.display(),
+                batch.len()
+            );
+        }
+    }
+
+    log::info!(
+        "{} inputs exceed the maximum of {max_inputs}, grouping each of {} directories separately",
         paths.len(),
-        groups.len()
+        by_dir.len()
+    );
+    by_dir.into_values().collect()
+}
+
+/// Merges (and, if `thumbnail`, thumbnails) the `n`th group, returning the resulting attachment(s).
+fn process_group(
+    n: usize,
+    group: &[lib::MediaInfo],
+    thumbnail: bool,
+    opts: &Options,
+    warnings: &lib::Warnings,
+) -> Vec<Attachment> {
+    assert!(!group.is_empty());
+
+    let timestamp = group.iter().map(|mi| mi.timestamp).min().unwrap();
+    let sources: Vec<_> = group.iter().map(Source::from).collect();
+    let group_id = lib::group_id(group);
+    let name0 = lib::file_name(&group[0].path).unwrap_or_default();
+    let thumb_ext = opts.thumb_ext();
+
+    if group.len() == 1 && group[0].is_image() {
+        let path = match &opts.out_dir_image {
+            Some(out_dir) if opts.dry_run => absolute(&out_dir.join(&name0)),
+            Some(out_dir) => {
+                let dest = out_dir.join(&name0);
+                // Lone streams (or images) are merged by copying them as-is
+                lib::merge(group, &dest, &opts.merge, warnings).unwrap();
+                set_mode(&dest, opts.chmod);
+                set_mtime(&dest, opts.preserve_mtime.then_some(timestamp));
+                std::fs::canonicalize(dest).unwrap()
+            }
+            None => canonicalize(&group[0].path),
+        };
+        return vec![Attachment {
+            name: name0.to_string(),
+            group_id,
+            size: group[0].size,
+            size_pretty: Size::from_bytes(group[0].size).to_string(),
+            timestamp,
+            thumbnail: Some(path.clone()),
+            path,
+            duration: Duration::ZERO.into(),
+            kind: lib::MergeKind::Image,
+            reencoded: false,
+            sources,
+            duplicate_of: None,
+            decode_errors: Vec::new(),
+        }];
+    }
+
+    // Try to use up to second _ as a prefix, new uuid otherwise.
+    let stub = lib::name_prefix(&group[0].path).unwrap_or_else(|| Uuid::now_v7().to_string());
+
+    // Each variant is merged into its own attachment, suffixed with its resolution if there
+    // are several.
+    // (The parts of a video split into parts share their resolution.)
+    let resolutions: BTreeSet<_> = group
+        .iter()
+        .filter(|mi| mi.is_video())
+        .map(|mi| mi.resolution)
+        .collect();
+    let variants: Vec<(String, Vec<lib::MediaInfo>)> =
+        if opts.keep_all_resolutions && resolutions.len() > 1 {
+            resolutions
+                .iter()
+                .map(|&resolution| {
+                    let suffix = match resolution {
+                        Some(resolution) => format!("_{resolution}"),
+                        None => String::new(),
+                    };
+                    let variant = group
+                        .iter()
+                        .filter(|mi| !mi.is_video() || mi.resolution == resolution)
+                        .cloned()
+                        .collect();
+                    (suffix, variant)
+                })
+                .collect()
+        } else {
+            vec![(String::new(), group.to_vec())]
+        };
+
+    variants
+        .into_iter()
+        .map(|(suffix, variant)| {
+            let ext = lib::output_extension(&variant, &opts.merge);
+            let out_dir = opts.out_dir(lib::merge_kind(&variant));
+            // The template may already tell variants apart by their resolution
+            let suffix = match opts.name_template.has_resolution() {
+                true => "",
+                false => suffix.as_str(),
+            };
+            let name = |n| format!("{}{suffix}", opts.name_template.render(&stub, n, &variant));
+            let names = |n| {
+                let mp4path = out_dir.join(format!("{}.{ext}", name(n)));
+                let thumb_path =
+                    thumbnail.then(|| out_dir.join(format!("{}.{thumb_ext}", name(n))));
+                std::iter::once(mp4path).chain(thumb_path).collect()
+            };
+            let n = match opts.dry_run {
+                true => free_names(n, names),
+                false => reserve_names(n, names),
+            };
+            let mp4name = format!("{}.{ext}", name(n));
+            let mp4path = out_dir.join(&mp4name);
+            if opts.dry_run {
+                let thumb_name = format!("{}.{thumb_ext}", name(n));
+                return Attachment {
+                    name: mp4name,
+                    group_id: group_id.clone(),
+                    path: absolute(&mp4path),
+                    timestamp,
+                    size: 0,
+                    size_pretty: Size::from_bytes(0).to_string(),
+                    kind: lib::merge_kind(&variant),
+                    reencoded: false,
+                    thumbnail: thumbnail.then(|| absolute(&out_dir.join(thumb_name))),
+                    duration: lib::merged_duration(&variant).into(),
+                    sources: variant.iter().map(Source::from).collect(),
+                    duplicate_of: None,
+                    decode_errors: Vec::new(),
+                };
+            }
+            let merged = lib::merge(&variant, &mp4path, &opts.merge, warnings).unwrap();
+            set_mode(&mp4path, opts.chmod);
+            set_mtime(&mp4path, opts.preserve_mtime.then_some(timestamp));
+
+            let decode_errors = if opts.verify_decode {
+                verify_decode(&mp4path, &opts.merge.tools, warnings)
+            } else {
+                Vec::new()
+            };
+
+            // The still of a live photo is its poster
+            let poster = variant
+                .iter()
+                .find(|image| {
+                    opts.group.live_photos
+                        && variant.iter().any(|video| lib::is_live_photo(image, video))
+                })
+                .map_or(mp4path.as_path(), |image| &image.path);
+            let thumbnail = thumbnail.then(|| {
+                let thumb_name = format!("{}.{thumb_ext}", name(n));
+                let thumb_path = out_dir.join(thumb_name);
+                generate_thumbnail(poster, &thumb_path, opts).unwrap();
+                set_mode(&thumb_path, opts.chmod);
+                set_mtime(&thumb_path, opts.preserve_mtime.then_some(timestamp));
+                std::fs::canonicalize(&thumb_path).unwrap()
+            });
+
+            let size = mp4path.metadata().unwrap().len();
+            Attachment {
+                name: mp4name,
+                group_id: group_id.clone(),
+                path: std::fs::canonicalize(mp4path).unwrap(),
+                timestamp,
+                size,
+                size_pretty: Size::from_bytes(size).to_string(),
+                kind: merged.kind,
+                reencoded: merged.reencoded,
+                thumbnail,
+                duration: lib::merged_duration(&variant).into(),
+                sources: variant.iter().map(Source::from).collect(),
+                duplicate_of: None,
+                decode_errors,
+            }
+        })
+        .collect()
+}
+
+/// Reserves the output files `paths(n)` by creating them (empty), unless any of them already
+/// exist, in which case the next `n` is tried. Creating them atomically keeps concurrent runs
+/// writing to the same directories from choosing the same names and clobbering one another's
+/// outputs. Returns the `n` reserved.
+fn reserve_names(mut n: usize, paths: impl Fn(usize) -> Vec<PathBuf>) -> usize {
+    loop {
+        let wanted = paths(n);
+        let mut reserved = Vec::new();
+        for path in &wanted {
+            let file = std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(path);
+            match file {
+                Ok(_) => reserved.push(path),
+                Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => break,
+                Err(err) => exit!("Error creating {}: {err}", path.display()),
+            }
+        }
+        if reserved.len() == wanted.len() {
+            return n;
+        }
+
+        for path in reserved {
+            let _ = std::fs::remove_file(path);
+        }
+        n += 1;
+    }
+}
+
+/// Finds the `n` for which none of the output files `paths(n)` exist yet, like
+/// [`reserve_names()`] but without creating them, for `--dry-run`.
+fn free_names(mut n: usize, paths: impl Fn(usize) -> Vec<PathBuf>) -> usize {
+    while paths(n).iter().any(|path| path.exists()) {
+        n += 1;
+    }
+    n
+}
+
+/// The absolute form of `path` (which may not exist yet, unlike with [`canonicalize()`]).
+fn absolute(path: &Path) -> PathBuf {
+    std::path::absolute(path)
+        .unwrap_or_else(|err| exit!("Error resolving {}: {err}", path.display()))
+}
+
+/// Decodes generated file `path` in full, warning about (and returning) any errors reported.
+fn verify_decode(path: &Path, tools: &lib::Tools, warnings: &lib::Warnings) -> Vec<String> {
+    let errors = lib::decode_errors(path, tools).unwrap_or_else(|err| vec![format!("{err:#}")]);
+    if !errors.is_empty() {
+        warnings.warn(
+            "decode-error",
+            Some(path),
+            format!(
+                "{}: {} errors decoding output, e.g. {}",
+                path.display(),
+                errors.len(),
+                errors[0]
+            ),
+        );
+    }
+    errors
+}
+
+/// Asks the user whether `mi` belongs in `group`, their durations differing by `delta`.
+fn confirm_grouping(mi: &lib::MediaInfo, group: &[lib::MediaInfo], delta: Duration) -> bool {
+    eprintln!("Ambiguous grouping, durations differ by {delta:?}:");
+    for (i, other) in std::iter::once(mi).chain(group).enumerate() {
+        let resolution = other.resolution.map(|r| r.to_string());
+        eprintln!(
+            "  {} {}: {}, {:?}, {}",
+            if i == 0 { "+" } else { " " },
+            other.path.display(),
+            other.media,
+            other.duration,
+            resolution.as_deref().unwrap_or("-")
+        );
+    }
+
+    loop {
+        eprint!("Group {} with the above? [y/n] ", mi.path.display());
+        let mut answer = String::new();
+        match std::io::stdin().read_line(&mut answer) {
+            // Keep the automatic choice if stdin is closed
+            Ok(0) | Err(_) => return true,
+            Ok(_) => (),
+        }
+        match answer.trim() {
+            "y" | "Y" | "yes" => return true,
+            "n" | "N" | "no" => return false,
+            _ => continue,
+        }
+    }
+}
+
+/// Parses a list of content hashes, one per line, ignoring anything after the hash (such as
+/// the file names `b3sum` lists) as well as blank lines and `#` comments.
+fn parse_hashes(list: &str) -> BTreeSet<String> {
+    list.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_whitespace().next())
+        .map(str::to_ascii_lowercase)
+        .collect()
+}
+
+/// Exits with [`EXIT_NO_INPUTS`], explaining how many inputs were `dropped` for which reasons.
+fn no_inputs_remain(dropped: &[(usize, &str)]) -> ! {
+    let total: usize = dropped.iter().map(|(n, _)| n).sum();
+    let reasons: Vec<_> = dropped
+        .iter()
+        .filter(|(n, _)| *n > 0)
+        .map(|(n, reason)| format!("{n} {reason}"))
+        .collect();
+    eprintln!(
+        "No inputs remained after filtering ({total} dropped: {})",
+        reasons.join(", ")
     );
+    std::process::exit(EXIT_NO_INPUTS);
+}
+
+/// Explains whether and why `a` and `b` would be grouped together, then exits.
+fn compare(a: &Path, b: &Path, opts: &Options) -> ! {
+    let warnings = match opts.quiet {
+        true => lib::Warnings::quiet(),
+        false => lib::Warnings::default(),
+    };
+    let [a, b] = [a, b].map(|path| {
+        lib::identify_with(path, &opts.group, &warnings).unwrap_or_else(|err| exit!("{err:#}"))
+    });
+    let cmp = lib::compare(&a, &b, &opts.group);
+
+    for mi in [&a, &b] {
+        let resolution = mi.resolution.map(|r| r.to_string());
+        eprintln!(
+            "{}: {} {} {}, {:?}, {}",
+            mi.path.display(),
+            mi.media,
+            mi.codec,
+            resolution.as_deref().unwrap_or("-"),
+            mi.duration,
+            mi.timestamp
+        );
+    }
+    eprintln!("grouping by:     {:?}", cmp.key);
+    eprintln!(
+        "duration delta:  {:?} (tolerance {:?})",
+        cmp.duration_delta, opts.group.max_delta
+    );
+    eprintln!("timestamp delta: {:#}", cmp.timestamp_delta);
+    eprintln!(
+        "name prefixes:   {:?} / {:?}",
+        cmp.prefixes[0], cmp.prefixes[1]
+    );
+    let verdict = if cmp.grouped {
+        "grouped"
+    } else {
+        "not grouped"
+    };
+    eprintln!("verdict:         {verdict}: {}", cmp.reason);
+
+    std::process::exit(0);
+}
+
+/// Reports whether ffmpeg and ffprobe are set up to support all our features, then exits.
+fn self_test(tools: &lib::Tools) -> ! {
+    let checks = lib::self_test(tools);
+    for check in &checks {
+        let status = if check.passed { "PASS" } else { "FAIL" };
+        match &check.detail {
+            Some(detail) => eprintln!(
+                "{status} {} (needed by {}): {detail}",
+                check.name, check.needed_by
+            ),
+            None => eprintln!("{status} {} (needed by {})", check.name, check.needed_by),
+        }
+    }
 
-    println!("{}", serde_json::to_string_pretty(&results).unwrap());
+    let failed = checks.iter().filter(|check| !check.passed).count();
+    if failed > 0 {
+        exit!("{failed} of {} checks failed", checks.len());
+    }
+    eprintln!("All {} checks passed", checks.len());
+    std::process::exit(0);
+}
+
+/// Rewrites the paths in `attachments` relative to (canonical) directory `base`, keeping (and
+/// warning about) paths outside of it as-is.
+fn relativize(attachments: &mut [Attachment], base: &Path, warnings: &lib::Warnings) {
+    let relative = |path: &mut PathBuf| {
+        if lib::is_url(path) {
+            return;
+        }
+        let absolute = canonicalize(path);
+        match absolute.strip_prefix(base) {
+            Ok(relative) => *path = relative.to_owned(),
+            Err(_) => {
+                warnings.warn(
+                    "outside-relative-base",
+                    Some(&absolute),
+                    format!(
+                        "{} is outside of {}, keeping its absolute path",
+                        absolute.display(),
+                        base.display()
+                    ),
+                );
+                *path = absolute;
+            }
+        }
+    };
+
+    for attachment in attachments {
+        // Images are their own thumbnails, don't warn twice
+        let own_thumbnail = attachment.thumbnail.as_ref() == Some(&attachment.path);
+        relative(&mut attachment.path);
+        if own_thumbnail {
+            attachment.thumbnail = Some(attachment.path.clone());
+        } else if let Some(thumbnail) = &mut attachment.thumbnail {
+            relative(thumbnail);
+        }
+        for source in &mut attachment.sources {
+            relative(&mut source.path);
+        }
+    }
+}
+
+/// The format [`print_results()`] prints in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    /// A (pretty-printed) array of attachments, or an object with a summary
+    Json,
+    /// One attachment per line, followed by a line with the summary
+    Ndjson,
+    /// One attachment per row, with a header row
+    Csv,
+}
+
+/// Prints `attachments` to stdout in `format`, along with `summary` if requested (as JSON, in
+/// an object wrapping them both).
+fn print_results(attachments: Vec<Attachment>, summary: Option<Summary>, format: OutputFormat) {
+    #[derive(Serialize)]
+    struct Output {
+        attachments: Vec<Attachment>,
+        summary: Summary,
+    }
+
+    match format {
+        OutputFormat::Json => {
+            let json = match summary {
+                Some(summary) => serde_json::to_string_pretty(&Output {
+                    attachments,
+                    summary,
+                }),
+                None => serde_json::to_string_pretty(&attachments),
+            };
+            println!("{}", json.unwrap());
+        }
+        OutputFormat::Ndjson => {
+            attachments.iter().for_each(print_line);
+            if let Some(summary) = summary {
+                print_line(&BTreeMap::from([("summary", summary)]));
+            }
+        }
+        OutputFormat::Csv => print_csv(&attachments),
+    }
+}
+
+/// Prints `value` as a single line of JSON, flushed right away so it can be consumed as soon
+/// as it's printed.
+fn print_line(value: &impl Serialize) {
+    let mut stdout = std::io::stdout().lock();
+    let _ = writeln!(stdout, "{}", serde_json::to_string(value).unwrap());
+    let _ = stdout.flush();
+}
+
+/// Prints `attachments` as CSV, one per row. Their sources (and decode errors) are listed by
+/// path, separated by `;`, and their sizes and durations are given as in JSON.
+fn print_csv(attachments: &[Attachment]) {
+    /// Quotes `field` if it contains anything CSV would otherwise take apart.
+    fn field(field: impl Display) -> String {
+        let field = field.to_string();
+        match field.contains([',', '"', '\n', '\r']) {
+            true => format!("\"{}\"", field.replace('"', "\"\"")),
+            false => field,
+        }
+    }
+
+    let mut stdout = std::io::stdout().lock();
+    let _ = writeln!(
+        stdout,
+        "name,group_id,path,timestamp,size,size_pretty,kind,reencoded,thumbnail,duration,\
+         sources,duplicate_of,decode_errors"
+    );
+    for attachment in attachments {
+        let sources: Vec<_> = attachment
+            .sources
+            .iter()
+            .map(|source| source.path.display().to_string())
+            .collect();
+        let row = [
+            field(&attachment.name),
+            field(&attachment.group_id),
+            field(attachment.path.display()),
+            field(attachment.timestamp),
+            field(attachment.size),
+            field(&attachment.size_pretty),
+            field(attachment.kind),
+            field(attachment.reencoded),
+            field(
+                attachment
+                    .thumbnail
+                    .as_deref()
+                    .map_or(String::new(), |thumbnail| thumbnail.display().to_string()),
+            ),
+            field(format_args!("{:#}", attachment.duration)),
+            field(sources.join(";")),
+            field(attachment.duplicate_of.as_deref().unwrap_or_default()),
+            field(attachment.decode_errors.join(";")),
+        ];
+        let _ = writeln!(stdout, "{}", row.join(","));
+    }
 }
 
 #[derive(Serialize)]
+struct Summary {
+    pub inputs: usize,
+    pub warnings: Vec<lib::Warning>,
+}
+
+impl Summary {
+    fn new(paths: &[PathBuf], warnings: Vec<lib::Warning>) -> Self {
+        Summary {
+            inputs: paths.len(),
+            warnings,
+        }
+    }
+}
+
+/// Whether `path` looks like a file produced by a previous run, i.e. it's named like the
+/// outputs of `template` and either lives in `out_dir` or sits next to the matching thumbnail
+/// (in any [`lib::ThumbnailFormat`]) we'd have generated for it.
+fn is_prior_output(path: &Path, out_dir: &Path, template: &lib::NameTemplate) -> bool {
+    if lib::is_url(path) {
+        return false;
+    }
+    let Some(stem) = path.file_stem().and_then(|stem| stem.to_str()) else {
+        return false;
+    };
+    if !template.matches(stem) {
+        return false;
+    }
+
+    let parent = match path.parent() {
+        Some(parent) if parent.as_os_str().is_empty() => Path::new("."),
+        Some(parent) => parent,
+        None => return false,
+    };
+    let in_out_dir = match (
+        std::fs::canonicalize(parent),
+        std::fs::canonicalize(out_dir),
+    ) {
+        (Ok(parent), Ok(out_dir)) => parent == out_dir,
+        _ => false,
+    };
+
+    in_out_dir
+        || lib::ThumbnailFormat::ALL
+            .iter()
+            .any(|format| path.with_extension(format.extension()).exists())
+}
+
+/// Applies permissions `mode` (if any) to generated file `path`.
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: Option<u32>) {
+    use std::os::unix::fs::PermissionsExt;
+
+    if let Some(mode) = mode {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+            .unwrap_or_else(|err| exit!("Error setting permissions of {}: {err}", path.display()));
+    }
+}
+
+/// Permissions are only supported on unix platforms.
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: Option<u32>) {}
+
+/// Sets the modification time of generated file `path` to `timestamp` (if any).
+fn set_mtime(path: &Path, timestamp: Option<Timestamp>) {
+    if let Some(timestamp) = timestamp {
+        std::fs::File::options()
+            .write(true)
+            .open(path)
+            .and_then(|file| file.set_modified(timestamp.into()))
+            .unwrap_or_else(|err| {
+                exit!(
+                    "Error setting modification time of {}: {err}",
+                    path.display()
+                )
+            });
+    }
+}
+
+/// Canonicalizes local input `path`, leaving URLs untouched.
+fn canonicalize(path: &Path) -> PathBuf {
+    if lib::is_url(path) {
+        return path.to_owned();
+    }
+    std::fs::canonicalize(path).unwrap()
+}
+
+/// The utility used to create `archive`, going by its extension.
+fn archive_tool(archive: &Path) -> Option<&'static str> {
+    let ext = archive.extension()?.to_ascii_lowercase();
+    match ext.to_str()? {
+        "zip" => Some("zip"),
+        "tar" => Some("tar"),
+        _ => None,
+    }
+}
+
+/// Drops the [`Source::streams`] of `attachments`, which are only output on request.
+fn strip_streams(attachments: &mut [Attachment]) {
+    for source in attachments.iter_mut().flat_map(|a| &mut a.sources) {
+        source.streams = Vec::new();
+    }
+}
+
+/// Bundles the files of `attachments`, along with a `manifest.json` listing them, into
+/// `archive` (a `.zip` or `.tar` file) using the `zip` or `tar` utility. The files are placed
+/// in the root of the archive and the manifest refers to them by name. With `remove`, the
+/// archived files are deleted afterwards, other than inputs passed through as-is.
+fn write_archive(archive: &Path, attachments: &[Attachment], remove: bool) -> std::io::Result<()> {
+    let tool = archive_tool(archive).unwrap();
+    let parent = match archive.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let archive = std::fs::canonicalize(parent)?.join(archive.file_name().unwrap());
+
+    // The files are gathered (by hard link, where possible) under the names they are archived
+    // as, so the archive is free of the directory structure of the outputs.
+    let staging = archive.with_file_name(format!(".instagrouper-{}", Uuid::now_v7()));
+    std::fs::create_dir(&staging)?;
+    let result = stage_archive(&staging, attachments).and_then(|names| {
+        // zip adds to an existing archive rather than replacing it
+        match std::fs::remove_file(&archive) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+        let status = Command::new(tool)
+            .args(if tool == "zip" {
+                ["-q", "-X"]
+            } else {
+                ["-c", "-f"]
+            })
+            .arg(&archive)
+            .args(&names)
+            .current_dir(&staging)
+            .status()?;
+        if !status.success() {
+            return Err(std::io::Error::other(format!(
+                "{tool} exited with {status}"
+            )));
+        }
+        Ok(())
+    });
+    let _ = std::fs::remove_dir_all(&staging);
+    result?;
+    log::info!(
+        "Archived {} attachments into {}",
+        attachments.len(),
+        archive.display()
+    );
+
+    if remove {
+        for attachment in attachments {
+            let files = std::iter::once(&attachment.path).chain(&attachment.thumbnail);
+            for file in files {
+                let source = attachment
+                    .sources
+                    .iter()
+                    .any(|source| canonicalize(&source.path) == *file);
+                if !source && !lib::is_url(file) && file.exists() {
+                    std::fs::remove_file(file)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Links the files of `attachments` and their manifest into `staging` for [`write_archive()`],
+/// returning their names.
+fn stage_archive(staging: &Path, attachments: &[Attachment]) -> std::io::Result<Vec<String>> {
+    let mut manifest = serde_json::to_value(attachments)?;
+    let mut names: BTreeMap<String, &Path> = BTreeMap::new();
+
+    for (attachment, entry) in attachments.iter().zip(manifest.as_array_mut().unwrap()) {
+        let files = [
+            ("path", Some(&attachment.path)),
+            ("thumbnail", attachment.thumbnail.as_ref()),
+        ];
+        for (field, file) in files {
+            let Some(file) = file.filter(|file| !lib::is_url(file)) else {
+                continue;
+            };
+            let name = lib::file_name(file).unwrap();
+            match names.get(&name) {
+                // Images are their own thumbnails
+                Some(&other) if other == file => {}
+                Some(other) => {
+                    return Err(std::io::Error::other(format!(
+                        "{} and {} would have the same name in the archive",
+                        other.display(),
+                        file.display()
+                    )));
+                }
+                None => {
+                    let dest = staging.join(&name);
+                    if std::fs::hard_link(file, &dest).is_err() {
+                        std::fs::copy(file, &dest)?;
+                    }
+                    names.insert(name.clone(), file);
+                }
+            }
+            entry[field] = name.into();
+        }
+    }
+
+    std::fs::write(
+        staging.join("manifest.json"),
+        serde_json::to_string_pretty(&manifest)?,
+    )?;
+    Ok(std::iter::once("manifest.json".to_owned())
+        .chain(names.into_keys())
+        .collect())
+}
+
+/// Merges `paths` into `output` as a single attachment, without grouping, naming, or
+/// thumbnailing them.
+fn passthrough_attachment(
+    paths: &[PathBuf],
+    output: &Path,
+    opts: &Options,
+    warnings: &lib::Warnings,
+) -> Attachment {
+    let group: Vec<_> = paths
+        .iter()
+        .map(|path| {
+            lib::identify_with(path, &opts.group, warnings)
+                .unwrap_or_else(|err| exit!("Error identifying {}: {err:#}", path.display()))
+        })
+        .collect();
+    let timestamp = group.iter().map(|mi| mi.timestamp).min().unwrap();
+
+    let merged = lib::merge(&group, output, &opts.merge, warnings)
+        .unwrap_or_else(|err| exit!("Error merging into {}: {err:#}", output.display()));
+    set_mode(output, opts.chmod);
+    set_mtime(output, opts.preserve_mtime.then_some(timestamp));
+
+    let size = output.metadata().unwrap().len();
+    Attachment {
+        name: lib::file_name(output).unwrap_or_default(),
+        group_id: lib::group_id(&group),
+        path: canonicalize(output),
+        timestamp,
+        size,
+        size_pretty: Size::from_bytes(size).to_string(),
+        kind: merged.kind,
+        reencoded: merged.reencoded,
+        thumbnail: None,
+        duration: lib::merged_duration(&group).into(),
+        sources: group.iter().map(Source::from).collect(),
+        duplicate_of: None,
+        decode_errors: Vec::new(),
+    }
+}
+
+/// Generates the thumbnail of `src` at `out`, an animated preview with `--animated-preview`.
+fn generate_thumbnail(src: &Path, out: &Path, opts: &Options) -> anyhow::Result<()> {
+    if opts.animated_preview {
+        let (frames, fps) = (lib::DEFAULT_PREVIEW_FRAMES, lib::DEFAULT_PREVIEW_FPS);
+        lib::animated_preview(src, out, frames, fps, &opts.thumbnail)
+    } else {
+        lib::thumbnail(src, out, &opts.thumbnail)
+    }
+}
+
+/// Exits listing any of `outputs` that already exist, unless `force`, or that would be written
+/// more than once (e.g. by inputs of the same name in different directories), even if `force`,
+/// as groups are processed concurrently and would clobber one another's outputs.
+fn refuse_clobbering<P: AsRef<Path>>(outputs: impl IntoIterator<Item = P>, force: bool) {
+    let mut seen = BTreeSet::new();
+    let mut conflicting = BTreeSet::new();
+    let outputs: Vec<_> = outputs.into_iter().collect();
+    for path in &outputs {
+        if !seen.insert(path.as_ref()) {
+            conflicting.insert(path.as_ref());
+        }
+    }
+    if !conflicting.is_empty() {
+        eprintln!("Refusing to write outputs more than once (inputs share their names):");
+        for path in conflicting {
+            eprintln!("  {}", path.display());
+        }
+        std::process::exit(1);
+    }
+
+    if force {
+        return;
+    }
+    let existing: Vec<_> = outputs
+        .iter()
+        .filter(|path| path.as_ref().exists())
+        .collect();
+    if !existing.is_empty() {
+        eprintln!("Refusing to overwrite existing outputs (pass --force to overwrite them):");
+        for path in existing {
+            eprintln!("  {}", path.as_ref().display());
+        }
+        std::process::exit(1);
+    }
+}
+
+/// The kind of attachment `mi` is as-is, without merging it with anything.
+fn thumbnail_only_kind(mi: &lib::MediaInfo) -> lib::MergeKind {
+    match mi.media {
+        lib::MediaType::Image => lib::MergeKind::Image,
+        lib::MediaType::Audio => lib::MergeKind::Audio,
+        lib::MediaType::Video if mi.stream_count > 1 => lib::MergeKind::AudioVideo,
+        lib::MediaType::Video => lib::MergeKind::Video,
+        lib::MediaType::Subtitle => {
+            exit!("{}: subtitles can't be thumbnailed", mi.path.display())
+        }
+    }
+}
+
+/// Where the thumbnail of `mi` is generated for `--thumbnail-only`, unless it's an image and
+/// so its own thumbnail.
+fn thumbnail_only_path(mi: &lib::MediaInfo, opts: &Options) -> Option<PathBuf> {
+    if mi.is_image() {
+        return None;
+    }
+    let name = lib::file_name(&mi.path).unwrap_or_else(|| Uuid::now_v7().to_string());
+    let stem = name.rsplit_once('.').map_or(&*name, |(stem, _)| stem);
+    let ext = opts.thumb_ext();
+    Some(
+        opts.out_dir(thumbnail_only_kind(mi))
+            .join(format!("{stem}.{ext}")),
+    )
+}
+
+/// Generates a thumbnail (at `thumb_path`, see [`thumbnail_only_path()`]) for `mi` as-is,
+/// without grouping or merging it with anything.
+fn thumbnail_only_attachment(
+    mi: &lib::MediaInfo,
+    thumb_path: Option<&Path>,
+    opts: &Options,
+) -> Attachment {
+    let path = &mi.path;
+    let canonical = canonicalize(path);
+    let name = lib::file_name(path).unwrap_or_else(|| Uuid::now_v7().to_string());
+
+    let kind = thumbnail_only_kind(mi);
+    let thumbnail = match thumb_path {
+        None => canonical.clone(),
+        Some(thumb_path) => {
+            generate_thumbnail(path, thumb_path, opts).unwrap();
+            set_mode(thumb_path, opts.chmod);
+            set_mtime(thumb_path, opts.preserve_mtime.then_some(mi.timestamp));
+            std::fs::canonicalize(thumb_path).unwrap()
+        }
+    };
+
+    Attachment {
+        name,
+        group_id: lib::group_id(std::slice::from_ref(mi)),
+        path: canonical,
+        timestamp: mi.timestamp,
+        size: mi.size,
+        size_pretty: Size::from_bytes(mi.size).to_string(),
+        kind,
+        reencoded: false,
+        thumbnail: Some(thumbnail),
+        duration: mi.duration.into(),
+        sources: vec![Source::from(mi)],
+        duplicate_of: None,
+        decode_errors: Vec::new(),
+    }
+}
+
+#[derive(Clone, Serialize)]
 struct Attachment {
     pub name: String,
+    /// Identifies the group the attachment was created from, shared by all attachments
+    /// created from the same group.
+    pub group_id: String,
     pub path: PathBuf,
     pub timestamp: Timestamp,
     pub size: u64,
     pub size_pretty: String,
-    pub kind: &'static str,
-    pub thumbnail: PathBuf,
+    pub kind: lib::MergeKind,
+    /// Whether any stream was re-encoded rather than copied into the attachment
+    pub reencoded: bool,
+    /// `None` if thumbnails were skipped with `--no-thumbnail` or `--thumb-limit`
+    pub thumbnail: Option<PathBuf>,
+    pub duration: lib::PrettyDuration,
+    pub sources: Vec<Source>,
+    /// The `group_id` of an attachment this one looks like a duplicate of
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duplicate_of: Option<String>,
+    /// The errors decoding the attachment reported, if checked with `--verify-decode`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub decode_errors: Vec<String>,
+}
+
+/// One of the inputs an [`Attachment`] was created from
+#[derive(Clone, Serialize)]
+struct Source {
+    pub path: PathBuf,
+    /// The role of the input in the attachment, i.e. its type of media
+    pub role: lib::MediaType,
+    pub resolution: Option<String>,
+    pub codec: String,
     pub duration: lib::PrettyDuration,
-    pub sources: Vec<PathBuf>,
+    /// The presentation time of the start of the input, in seconds
+    pub start_time: Option<f64>,
+    /// The language the input is tagged with, if any
+    pub language: Option<String>,
+    /// Every stream of the input, with `--probe-streams all`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub streams: Vec<lib::StreamInfo>,
+}
+
+impl From<&lib::MediaInfo> for Source {
+    fn from(mi: &lib::MediaInfo) -> Self {
+        Source {
+            path: mi.path.clone(),
+            role: mi.media.clone(),
+            resolution: mi.resolution.map(|resolution| resolution.to_string()),
+            codec: mi.codec.clone(),
+            duration: mi.duration.into(),
+            start_time: mi.start_time.map(|start| start.as_secs_f64()),
+            language: mi.language.clone(),
+            streams: mi.streams.clone(),
+        }
+    }
 }
 
 trait OrExit {
@@ -0,0 +1,60 @@
+use std::process::Command;
+
+#[test]
+fn no_inputs_prints_usage() {
+    let output = Command::new(env!("CARGO_BIN_EXE_instagrouper"))
+        .output()
+        .unwrap();
+
+    assert_eq!(output.status.code(), Some(2));
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.starts_with("Usage: instagrouper"));
+    // No empty error message after the usage
+    assert!(!stderr.ends_with("\n\n"));
+}
+
+#[test]
+fn no_thumbnail_conflicts_with_thumbnail_only() {
+    let output = Command::new(env!("CARGO_BIN_EXE_instagrouper"))
+        .args(["--no-thumbnail", "--thumbnail-only"])
+        .output()
+        .unwrap();
+
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("mutually exclusive"));
+}
+
+#[test]
+fn summary_conflicts_with_csv() {
+    let output = Command::new(env!("CARGO_BIN_EXE_instagrouper"))
+        .args(["--summary", "--format", "csv"])
+        .output()
+        .unwrap();
+
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("--format csv"));
+}
+
+#[test]
+fn directories_are_scanned_for_media() {
+    let dir = std::env::temp_dir().join(format!("instagrouper-scan-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("nested")).unwrap();
+    std::fs::write(dir.join("notes.txt"), "").unwrap();
+    std::fs::write(dir.join("nested").join("clip.mp4"), "").unwrap();
+
+    // Without --recursive, only the top level (and its text file) is scanned
+    let output = Command::new(env!("CARGO_BIN_EXE_instagrouper"))
+        .arg(&dir)
+        .output()
+        .unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(output.status.code(), Some(3));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("1 without a media extension"), "{stderr}");
+}